@@ -1,4 +1,29 @@
+use crate::sync::LockRank;
+
 pub const MAX_PROCESSES: usize = 4;
 pub const MAX_THREADS: usize = 2;
+pub const MAX_HARTS: usize = 5; // Keep in sync with consts.S
+
+pub const STARVATION_THRESHOLD: u32 = 1 << 20;
+pub const DEFAULT_QUANTUM_MS: u64 = 1000; // Also the longest a thread can ask for
+pub const RUNAWAY_PREEMPTIONS: u32 = 10; // Preemptions in a row before a thread is demoted
 
 pub const DEFAULT_STACK_SIZE: usize = 4096;
+
+pub const CONSOLE_BUFFER_SIZE: usize = 256;
+pub const CONSOLE_PROMPT: &str = "eepy> ";
+
+pub const MAX_PIPES: usize = 4;
+pub const PIPE_CAPACITY: usize = 128; // bytes
+
+pub const MAX_LOCK_ACQUIRE_SPINS: usize = 10_000_000; // Backoff spins before giving up
+
+// Nested locks must be taken in increasing rank, checked with the lock_ranks feature
+pub const LOCK_RANK_PIPES: LockRank = 1;
+pub const LOCK_RANK_SCHEDULER: LockRank = 2;
+pub const LOCK_RANK_TIMER_WHEEL: LockRank = 3;
+pub const LOCK_RANK_SLAB_ALLOCATOR: LockRank = 4; // Takes pages while held
+pub const LOCK_RANK_PAGE_ALLOCATOR: LockRank = 5;
+pub const MAX_IO_ATTEMPTS: usize = 10_000_000;
+
+pub const WATCHDOG_TIMEOUT_MS: u64 = 10_000;
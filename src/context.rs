@@ -1,5 +1,7 @@
 use core::arch::global_asm;
 
+use crate::{print, println};
+
 #[repr(C, align(8))]
 #[derive(Clone, Copy)]
 pub struct RegisterContext {
@@ -143,10 +145,118 @@ impl RegisterContext {
             ft11: 0,
         }
     }
+    // Argument registers by ABI index, a0 through a7
+    pub fn arg(&self, n: usize) -> u64 {
+        match n {
+            0 => self.a0,
+            1 => self.a1,
+            2 => self.a2,
+            3 => self.a3,
+            4 => self.a4,
+            5 => self.a5,
+            6 => self.a6,
+            7 => self.a7,
+            _ => panic!("There is no argument register a{}", n),
+        }
+    }
+
+    pub fn set_arg(&mut self, n: usize, val: u64) {
+        let reg = match n {
+            0 => &mut self.a0,
+            1 => &mut self.a1,
+            2 => &mut self.a2,
+            3 => &mut self.a3,
+            4 => &mut self.a4,
+            5 => &mut self.a5,
+            6 => &mut self.a6,
+            7 => &mut self.a7,
+            _ => panic!("There is no argument register a{}", n),
+        };
+        *reg = val;
+    }
+
+    pub fn dump(&self) {
+        let registers = [
+            ("ra", self.ra),
+            ("sp", self.sp),
+            ("gp", self.gp),
+            ("tp", self.tp),
+            ("t0", self.t0),
+            ("t1", self.t1),
+            ("t2", self.t2),
+            ("s0", self.s0),
+            ("s1", self.s1),
+            ("a0", self.a0),
+            ("a1", self.a1),
+            ("a2", self.a2),
+            ("a3", self.a3),
+            ("a4", self.a4),
+            ("a5", self.a5),
+            ("a6", self.a6),
+            ("a7", self.a7),
+            ("s2", self.s2),
+            ("s3", self.s3),
+            ("s4", self.s4),
+            ("s5", self.s5),
+            ("s6", self.s6),
+            ("s7", self.s7),
+            ("s8", self.s8),
+            ("s9", self.s9),
+            ("s10", self.s10),
+            ("s11", self.s11),
+            ("t3", self.t3),
+            ("t4", self.t4),
+            ("t5", self.t5),
+            ("t6", self.t6),
+            ("ft0", self.ft0),
+            ("ft1", self.ft1),
+            ("ft2", self.ft2),
+            ("ft3", self.ft3),
+            ("ft4", self.ft4),
+            ("ft5", self.ft5),
+            ("ft6", self.ft6),
+            ("ft7", self.ft7),
+            ("fs0", self.fs0),
+            ("fs1", self.fs1),
+            ("fa0", self.fa0),
+            ("fa1", self.fa1),
+            ("fa2", self.fa2),
+            ("fa3", self.fa3),
+            ("fa4", self.fa4),
+            ("fa5", self.fa5),
+            ("fa6", self.fa6),
+            ("fa7", self.fa7),
+            ("fs2", self.fs2),
+            ("fs3", self.fs3),
+            ("fs4", self.fs4),
+            ("fs5", self.fs5),
+            ("fs6", self.fs6),
+            ("fs7", self.fs7),
+            ("fs8", self.fs8),
+            ("fs9", self.fs9),
+            ("fs10", self.fs10),
+            ("fs11", self.fs11),
+            ("ft8", self.ft8),
+            ("ft9", self.ft9),
+            ("ft10", self.ft10),
+            ("ft11", self.ft11),
+        ];
+        for row in registers.chunks(4) {
+            for (name, val) in row {
+                print!("{:>4}: {:#018x}  ", name, val);
+            }
+            println!();
+        }
+    }
 }
 
 extern "C" {
-    pub fn activate_context(pc: u64, context_base: u64, hart_id: u64) -> ActivationResult;
+    pub fn activate_context(
+        pc: u64,
+        context_base: u64,
+        hart_id: u64,
+        fpu_enabled: u64,
+    ) -> ActivationResult;
     pub fn init_context();
 }
 
@@ -1,23 +1,45 @@
 use alloc::{alloc::Global, boxed::Box, vec::Vec};
 use core::{
     alloc::Allocator,
-    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    cell::UnsafeCell,
+    fmt::Debug,
+    iter,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    },
     usize,
 };
 
+use crate::io::{Readable, Writable};
+
 pub struct AtomicBitVec<A: Allocator = Global> {
     inner: Box<[AtomicUsize], A>,
-    _length: usize,
+    length: usize,
 }
 
 impl<A: Allocator> AtomicBitVec<A> {
+    #[allow(dead_code)]
     pub fn new_in(size: usize, allocator: A) -> AtomicBitVec<A> {
         let num_elems = size.div_ceil(usize::BITS as usize);
         let mut inner = Vec::with_capacity_in(num_elems, allocator);
         inner.extend((0..num_elems).map(|_| AtomicUsize::new(0)));
         AtomicBitVec {
             inner: inner.into_boxed_slice(),
-            _length: size,
+            length: size,
+        }
+    }
+
+    // Same as new_in, but zeroes all the words at once instead of storing each one
+    pub fn new_zeroed_in(size: usize, allocator: A) -> AtomicBitVec<A> {
+        let num_elems = size.div_ceil(usize::BITS as usize);
+        // An all zero AtomicUsize is just 0
+        let inner = unsafe { Box::new_zeroed_slice_in(num_elems, allocator).assume_init() };
+        AtomicBitVec {
+            inner,
+            length: size,
         }
     }
 
@@ -43,6 +65,78 @@ impl<A: Allocator> AtomicBitVec<A> {
         Some(val)
     }
 
+    // The word API is for scanning only. Each word is loaded on its own, so a scan isn't a
+    // consistent view of the whole vector; anything that acts on a bit should use get and set.
+    #[allow(dead_code)]
+    pub fn snapshot_word(&self, word_index: usize) -> Option<usize> {
+        Some(self.inner.get(word_index)?.load(Relaxed))
+    }
+
+    #[allow(dead_code)]
+    pub fn for_each_word(&self, mut f: impl FnMut(usize, usize)) {
+        self.inner
+            .iter()
+            .enumerate()
+            .for_each(|(word_index, val)| f(word_index, val.load(Relaxed)));
+    }
+
+    #[allow(dead_code)]
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        self.inner
+            .iter()
+            .enumerate()
+            .flat_map(|(inner_index, val)| {
+                let mut packed = val.load(Relaxed);
+                let base = inner_index * usize::BITS as usize;
+                iter::from_fn(move || match packed {
+                    0 => None,
+                    _ => {
+                        let inner_offset = packed.trailing_zeros() as usize;
+                        packed &= packed - 1;
+                        Some(base + inner_offset)
+                    }
+                })
+            })
+            .take_while(|index| *index < self.length)
+    }
+
+    #[allow(dead_code)]
+    pub fn find_first_run(&self, len: usize) -> Option<usize> {
+        if len == 0 {
+            return Some(0);
+        }
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for (inner_index, val) in self.inner.iter().enumerate() {
+            let base = inner_index * usize::BITS as usize;
+            let width = (self.length - base).min(usize::BITS as usize);
+            let packed = val.load(Relaxed);
+            let mut inner_offset = 0;
+            // Skip over whole runs of ones and zeros at a time instead of testing each bit
+            while inner_offset < width {
+                let ones =
+                    ((packed >> inner_offset).trailing_ones() as usize).min(width - inner_offset);
+                if ones > 0 {
+                    if run_len == 0 {
+                        run_start = base + inner_offset;
+                    }
+                    run_len += ones;
+                    if run_len >= len {
+                        return Some(run_start);
+                    }
+                    inner_offset += ones;
+                }
+                if inner_offset < width {
+                    let zeros = ((packed >> inner_offset).trailing_zeros() as usize)
+                        .min(width - inner_offset);
+                    run_len = 0;
+                    inner_offset += zeros;
+                }
+            }
+        }
+        None
+    }
+
     pub fn _find_false(&self) -> Option<usize> {
         for (index, val) in self.inner.iter().enumerate() {
             let packed = val.load(Relaxed);
@@ -129,7 +223,253 @@ impl<A: Allocator> AtomicBitVec<A> {
         }
     }
 
+    // Like _bulk_write over lo_index..=hi_index, but only writes if no bit in the range already
+    // holds val, since that would mean something was set or cleared twice. Otherwise returns the
+    // first such index. The check and the write are separate, so concurrent writers to the range
+    // need to be excluded some other way.
+    #[allow(dead_code)]
+    pub fn set_range_checked(
+        &self,
+        lo_index: usize,
+        hi_index: usize,
+        val: bool,
+    ) -> Result<usize, usize> {
+        assert!(lo_index <= hi_index && hi_index < self.length);
+        let bits = usize::BITS as usize;
+        let lo_inner_index = lo_index / bits;
+        let hi_inner_index = hi_index / bits;
+        for inner_index in lo_inner_index..=hi_inner_index {
+            let lo_offset = match inner_index == lo_inner_index {
+                true => lo_index % bits,
+                false => 0,
+            };
+            let hi_offset = match inner_index == hi_inner_index {
+                true => hi_index % bits,
+                false => bits - 1,
+            };
+            let mask = (usize::MAX << lo_offset) & (usize::MAX >> (bits - 1 - hi_offset));
+            let packed = self.inner[inner_index].load(Relaxed);
+            let conflicts = match val {
+                true => packed & mask,
+                false => !packed & mask,
+            };
+            if conflicts != 0 {
+                return Err(inner_index * bits + conflicts.trailing_zeros() as usize);
+            }
+        }
+        self._bulk_write(lo_index, hi_index, val);
+        Ok(hi_index - lo_index + 1)
+    }
+
     pub fn _len(&self) -> usize {
-        self._length
+        self.length
+    }
+}
+
+impl<A: Allocator> Debug for AtomicBitVec<A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Bits are printed in index order (bit 0 first), one group per word
+        for (inner_index, val) in self.inner.iter().enumerate() {
+            let base = inner_index * usize::BITS as usize;
+            let width = (self.length - base).min(usize::BITS as usize);
+            if inner_index > 0 {
+                write!(f, " ")?;
+            }
+            let ordered = val.load(Relaxed).reverse_bits() >> (usize::BITS as usize - width);
+            write!(f, "{:0width$b}", ordered, width = width)?;
+        }
+        Ok(())
+    }
+}
+
+struct ChannelSlot<T> {
+    // Relative to the slot's index, so a fresh channel is all zeroes and can be built in a const.
+    // Equal to the position when the slot is free for that position's sender, and one past it once
+    // the value is written.
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// A bounded ring that any number of threads can send to and receive from without locking. Each
+// slot's sequence number says whose turn it is, so a sender only has to win the race for a position
+// and never waits on a slow receiver (or the reverse).
+pub struct Channel<T, const N: usize> {
+    slots: [ChannelSlot<T>; N],
+    send_position: AtomicUsize,
+    receive_position: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Channel<T, N> {}
+
+#[allow(dead_code)]
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Channel<T, N> {
+        // With a single slot, a written value would look free to the next sender
+        assert!(N > 1);
+        Channel {
+            slots: [const {
+                ChannelSlot {
+                    sequence: AtomicUsize::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+            send_position: AtomicUsize::new(0),
+            receive_position: AtomicUsize::new(0),
+        }
+    }
+
+    fn slot(&self, position: usize) -> (&ChannelSlot<T>, usize) {
+        let index = position % N;
+        (&self.slots[index], index)
+    }
+
+    // Hands the value back if the channel is full
+    pub fn try_send(&self, val: T) -> Result<(), T> {
+        let mut position = self.send_position.load(Relaxed);
+        loop {
+            let (slot, index) = self.slot(position);
+            let sequence = slot.sequence.load(Acquire).wrapping_add(index);
+            match sequence.wrapping_sub(position) as isize {
+                0 => match self.send_position.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Relaxed,
+                    Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(val) };
+                        slot.sequence
+                            .store(position.wrapping_add(1).wrapping_sub(index), Release);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                },
+                // Still holds the value from a lap ago
+                lag if lag < 0 => return Err(val),
+                // Another sender got here first
+                _ => position = self.send_position.load(Relaxed),
+            }
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        let mut position = self.receive_position.load(Relaxed);
+        loop {
+            let (slot, index) = self.slot(position);
+            let sequence = slot.sequence.load(Acquire).wrapping_add(index);
+            match sequence.wrapping_sub(position.wrapping_add(1)) as isize {
+                0 => match self.receive_position.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Relaxed,
+                    Relaxed,
+                ) {
+                    Ok(_) => {
+                        let val = unsafe { (*slot.value.get()).assume_init_read() };
+                        // Free for the sender one lap ahead
+                        slot.sequence
+                            .store(position.wrapping_add(N).wrapping_sub(index), Release);
+                        return Some(val);
+                    }
+                    Err(current) => position = current,
+                },
+                // Nothing sent to this position yet
+                lag if lag < 0 => return None,
+                // Another receiver got here first
+                _ => position = self.receive_position.load(Relaxed),
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Drop for Channel<T, N> {
+    fn drop(&mut self) {
+        while self.try_recv().is_some() {}
+    }
+}
+
+// A byte ring for one producer and one consumer, such as a UART receive interrupt and the console.
+// The positions only ever count up, so a full ring and an empty one look different without giving
+// up a slot.
+pub struct RingBuffer<const N: usize> {
+    bytes: UnsafeCell<[u8; N]>,
+    head: AtomicUsize, // Next position to pop, only moved by the consumer
+    tail: AtomicUsize, // Next position to push, only moved by the producer
+}
+
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+#[allow(dead_code)]
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> RingBuffer<N> {
+        assert!(N > 0);
+        RingBuffer {
+            bytes: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Acquire);
+        self.tail.load(Acquire).wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    // Only the producer may push
+    pub fn push(&self, byte: u8) -> Result<(), ()> {
+        match self.push_slice(&[byte]) {
+            1 => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    // Pushes as much of the slice as fits, in order, and returns how many bytes that was
+    pub fn push_slice(&self, bytes: &[u8]) -> usize {
+        let tail = self.tail.load(Relaxed);
+        let free = N - tail.wrapping_sub(self.head.load(Acquire));
+        let count = bytes.len().min(free);
+        // At most two copies, one up to the end of the buffer and one from the start
+        let start = tail % N;
+        let first = count.min(N - start);
+        unsafe {
+            let buffer = self.bytes.get() as *mut u8;
+            ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.add(start), first);
+            ptr::copy_nonoverlapping(bytes.as_ptr().add(first), buffer, count - first);
+        }
+        self.tail.store(tail.wrapping_add(count), Release);
+        count
+    }
+
+    // Only the consumer may pop
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Relaxed);
+        if head == self.tail.load(Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*self.bytes.get())[head % N] };
+        self.head.store(head.wrapping_add(1), Release);
+        Some(byte)
+    }
+}
+
+// Gives the ring read_blocking and write_blocking, which spin with backoff until the other side
+// catches up, so the other side has to be on another hart. Still one producer and one consumer.
+impl<const N: usize> Readable<u8> for RingBuffer<N> {
+    fn read(&self) -> Option<u8> {
+        self.pop()
+    }
+}
+
+impl<const N: usize> Writable<u8> for RingBuffer<N> {
+    fn write(&self, v: u8) -> Result<(), ()> {
+        self.push(v)
     }
 }
@@ -2,8 +2,40 @@ use core::arch::global_asm;
 
 use crate::println;
 
+const MAX_BACKTRACE_DEPTH: usize = 16;
+
 extern "C" {
     pub fn test_context_asm() -> u64;
+    fn get_frame_pointer() -> u64;
+    fn get_kernel_stack_bounds() -> KernelStackBounds;
+}
+
+#[repr(C)]
+struct KernelStackBounds {
+    lowest: u64,
+    highest: u64,
+}
+
+// Follows the saved frame pointers up the kernel stack, printing each return address. Frames sit
+// just below the frame pointer as [.., previous fp, ra], and callers' frames are always higher.
+pub fn print_backtrace() {
+    let bounds = unsafe { get_kernel_stack_bounds() };
+    let mut fp = unsafe { get_frame_pointer() };
+    println!("Backtrace:");
+    for depth in 0..MAX_BACKTRACE_DEPTH {
+        if fp % 8 != 0 || fp < bounds.lowest + 16 || fp > bounds.highest {
+            break;
+        }
+        let (ra, previous_fp) = unsafe { (*((fp - 8) as *const u64), *((fp - 16) as *const u64)) };
+        if ra == 0 {
+            break;
+        }
+        println!("  {:>2}: {:#018x}", depth, ra);
+        if previous_fp <= fp {
+            break;
+        }
+        fp = previous_fp;
+    }
 }
 
 #[no_mangle]
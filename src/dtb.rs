@@ -0,0 +1,217 @@
+use core::slice;
+
+use crate::{heap::set_ram_range, time::set_timer_freq, uart::set_uart0_base};
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+const MAX_DEPTH: usize = 16;
+
+// Defaults from the devicetree spec, for when the root node doesn't say
+const DEFAULT_ADDRESS_CELLS: usize = 2;
+const DEFAULT_SIZE_CELLS: usize = 1;
+
+// Everything we pull out of the device tree. Missing entries are left to the built in defaults.
+#[derive(Default)]
+struct DeviceInfo {
+    memory: Option<(u64, u64)>, // (base, size)
+    timebase_frequency: Option<u64>,
+    uart_base: Option<u64>,
+}
+
+struct FlattenedDeviceTree<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let word = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(word.try_into().ok()?))
+}
+
+// Combines big endian 32 bit cells into one value
+fn read_cells(bytes: &[u8], cells: usize) -> Option<u64> {
+    (0..cells).try_fold(0u64, |acc, cell| {
+        Some(acc << 32 | read_u32(bytes, cell * 4)? as u64)
+    })
+}
+
+// Strings in the tree are nul terminated
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    match bytes.iter().position(|&byte| byte == 0) {
+        Some(end) => &bytes[..end],
+        None => bytes,
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |acc, &digit| {
+        let value = (digit as char).to_digit(16)?;
+        acc.checked_mul(16)?.checked_add(value as u64)
+    })
+}
+
+// "/soc/serial@10000000:115200" -> 0x10000000
+fn unit_address(path: &[u8]) -> Option<u64> {
+    let path = path.split(|&byte| byte == b':').next()?;
+    let node = path.rsplit(|&byte| byte == b'/').next()?;
+    let at = node.iter().position(|&byte| byte == b'@')?;
+    parse_hex(&node[at + 1..])
+}
+
+impl<'a> FlattenedDeviceTree<'a> {
+    unsafe fn from_ptr(dtb: *const u8) -> Option<FlattenedDeviceTree<'a>> {
+        if dtb.is_null() {
+            return None;
+        }
+        let header = slice::from_raw_parts(dtb, FDT_HEADER_SIZE);
+        if read_u32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = read_u32(header, 4)? as usize;
+        let tree = slice::from_raw_parts(dtb, total_size);
+        let structs_offset = read_u32(header, 8)? as usize;
+        let strings_offset = read_u32(header, 12)? as usize;
+        let strings_size = read_u32(header, 32)? as usize;
+        let structs_size = read_u32(header, 36)? as usize;
+        Some(FlattenedDeviceTree {
+            structs: tree.get(structs_offset..structs_offset.checked_add(structs_size)?)?,
+            strings: tree.get(strings_offset..strings_offset.checked_add(strings_size)?)?,
+        })
+    }
+
+    // Calls `visit(depth, node_name, property_name, value)` for every property, where the root node
+    // is at depth 1 and has an empty name. Stops quietly at anything malformed.
+    fn for_each_property(&self, mut visit: impl FnMut(usize, &'a [u8], &'a [u8], &'a [u8])) {
+        let mut names: [&[u8]; MAX_DEPTH] = [&[]; MAX_DEPTH];
+        let mut depth = 0;
+        let mut offset = 0;
+        while let Some(token) = read_u32(self.structs, offset) {
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let Some(rest) = self.structs.get(offset..) else {
+                        return;
+                    };
+                    let name = until_nul(rest);
+                    if depth == MAX_DEPTH {
+                        return;
+                    }
+                    names[depth] = name;
+                    depth += 1;
+                    offset = align4(offset + name.len() + 1);
+                }
+                FDT_END_NODE => match depth.checked_sub(1) {
+                    Some(parent_depth) => depth = parent_depth,
+                    None => return,
+                },
+                FDT_PROP => {
+                    let (Some(len), Some(name_offset)) = (
+                        read_u32(self.structs, offset),
+                        read_u32(self.structs, offset + 4),
+                    ) else {
+                        return;
+                    };
+                    let value_start = offset + 8;
+                    let (Some(value), Some(name)) = (
+                        self.structs.get(value_start..value_start + len as usize),
+                        self.strings.get(name_offset as usize..),
+                    ) else {
+                        return;
+                    };
+                    if depth == 0 {
+                        return;
+                    }
+                    visit(depth, names[depth - 1], until_nul(name), value);
+                    offset = align4(value_start + len as usize);
+                }
+                FDT_NOP => (),
+                FDT_END => return,
+                _ => return,
+            }
+        }
+    }
+
+    fn alias(&self, alias: &[u8]) -> Option<&'a [u8]> {
+        let mut path = None;
+        self.for_each_property(|depth, node, property, value| {
+            if depth == 2 && node == b"aliases" && property == alias {
+                path = Some(until_nul(value));
+            }
+        });
+        path
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        let mut info = DeviceInfo::default();
+        let mut address_cells = DEFAULT_ADDRESS_CELLS;
+        let mut size_cells = DEFAULT_SIZE_CELLS;
+        let mut stdout_path = None;
+        // The root's properties always come before its children, so the cell sizes are known in
+        // time for the memory node
+        self.for_each_property(
+            |depth, node, property, value| match (depth, node, property) {
+                (1, _, b"#address-cells") => {
+                    address_cells = read_u32(value, 0).map_or(address_cells, |cells| cells as usize)
+                }
+                (1, _, b"#size-cells") => {
+                    size_cells = read_u32(value, 0).map_or(size_cells, |cells| cells as usize)
+                }
+                (2, _, b"reg") if node == b"memory" || node.starts_with(b"memory@") => {
+                    if info.memory.is_none() {
+                        let base = read_cells(value, address_cells);
+                        let size = value
+                            .get(address_cells * 4..)
+                            .and_then(|size| read_cells(size, size_cells));
+                        info.memory = base.zip(size);
+                    }
+                }
+                (2, b"cpus", b"timebase-frequency") => {
+                    info.timebase_frequency = read_cells(value, value.len() / 4)
+                }
+                (2, b"chosen", b"stdout-path") => stdout_path = Some(until_nul(value)),
+                _ => (),
+            },
+        );
+        info.uart_base = stdout_path.and_then(|path| match path.first() {
+            Some(b'/') => unit_address(path),
+            // Not a path, so it names an alias, possibly followed by options
+            _ => self
+                .alias(path.split(|&byte| byte == b':').next()?)
+                .and_then(unit_address),
+        });
+        info
+    }
+}
+
+// Overrides the built in memory size, timer frequency, and UART base with whatever the device tree
+// provides. Must run before anything prints or allocates. Returns false if there's no valid tree.
+pub unsafe fn init(dtb: *const u8) -> bool {
+    let Some(tree) = FlattenedDeviceTree::from_ptr(dtb) else {
+        return false;
+    };
+    let info = tree.device_info();
+    if let Some((base, size)) = info.memory {
+        set_ram_range(base, size);
+    }
+    if let Some(frequency) = info.timebase_frequency {
+        set_timer_freq(frequency);
+    }
+    if let Some(base) = info.uart_base {
+        set_uart0_base(base);
+    }
+    true
+}
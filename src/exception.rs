@@ -4,7 +4,8 @@ use crate::{
     println,
     reg::get_stval,
     syscall::handle_syscall,
-    thread::{ThreadActivationResult, ThreadHandle},
+    thread::{ResumeMode, ThreadActivationResult, ThreadHandle},
+    trap_stats::record_exception,
 };
 
 pub const INSTUCTION_ADDRESS_MISALIGNED: u64 = 0;
@@ -27,24 +28,62 @@ extern "C" {
 
 global_asm!(include_str!("exception.S"));
 
+pub fn exception_name(code: u64) -> &'static str {
+    match code {
+        INSTUCTION_ADDRESS_MISALIGNED => "Instruction Address Misaligned",
+        INSTRUCTION_ACCESS_FAULT => "Instruction Access Fault",
+        ILLEGAL_INSTRUCTION => "Illegal Instruction",
+        BREAKPOINT => "Breakpoint",
+        LOAD_ADDRESS_MISALIGNED => "Load Address Misaligned",
+        LOAD_ACCESS_FAULT => "Load Access Fault",
+        STORE_AMO_ADDRESS_MISALIGNED => "Store AMO Address Misaligned",
+        STORE_AMO_ACCESS_FAULT => "Store AMO Access Fault",
+        USER_ENVIRONMENT_CALL => "User Environment Call",
+        SUPERVISOR_ENVIRONMENT_CALL => "Supervisor Environment Call",
+        INSTRUCTION_PAGE_FAULT => "Instruction Page Fault",
+        LOAD_PAGE_FAULT => "Load Page Fault",
+        STORE_AMO_PAGE_FAULT => "Store AMO Page Fault",
+        _ => "Unknown Exception",
+    }
+}
+
 pub fn handle_exception(activation: &ThreadActivationResult, handle: &ThreadHandle) {
+    record_exception(activation.cause);
     match activation.cause {
-        INSTUCTION_ADDRESS_MISALIGNED => unimplemented!("Instruction Address Misaligned"),
-        INSTRUCTION_ACCESS_FAULT => unimplemented!("Instruction Access Fault"),
-        ILLEGAL_INSTRUCTION => handle.kill(),
-        BREAKPOINT => unimplemented!("Breakpoint"),
-        LOAD_ADDRESS_MISALIGNED => handle.kill(),
-        LOAD_ACCESS_FAULT => {
-            println!("Error at: {:#010x}", unsafe { get_stval() });
-            unimplemented!("Load Access Fault");
+        ILLEGAL_INSTRUCTION => match handle.enable_fpu() {
+            true => handle.resolve_interrupt_or_kill(ResumeMode::Retry), // Retry with the FPU on
+            false => handle.kill(),
+        },
+        BREAKPOINT => {
+            println!(
+                "Breakpoint hit by thread {} at {:#010x}",
+                activation.thread.id(),
+                activation.thread.pc()
+            );
+            activation.thread.registers().dump();
+            handle.resolve_interrupt_or_kill(ResumeMode::Skip);
         }
+        LOAD_ADDRESS_MISALIGNED => handle.kill(),
         STORE_AMO_ADDRESS_MISALIGNED => handle.kill(),
-        STORE_AMO_ACCESS_FAULT => unimplemented!("Store AMO Access Fault"),
         USER_ENVIRONMENT_CALL => handle_syscall(activation, handle, false),
         SUPERVISOR_ENVIRONMENT_CALL => handle_syscall(activation, handle, true),
-        INSTRUCTION_PAGE_FAULT => unimplemented!("Instruction Page Fault"),
-        LOAD_PAGE_FAULT => unimplemented!("Load Page Fault"),
-        STORE_AMO_PAGE_FAULT => unimplemented!("Store AMO Page Fault"),
-        reason => panic!("Unknown exception encountered: {:#010x}", reason),
+        _ => fault_report(activation, handle),
     }
 }
+
+fn fault_report(activation: &ThreadActivationResult, handle: &ThreadHandle) {
+    println!(
+        "{} ({:#x}) in thread {} of process {}",
+        exception_name(activation.cause),
+        activation.cause,
+        activation.thread.id(),
+        activation.thread.owning_process_id()
+    );
+    println!(
+        "pc: {:#018x}  stval: {:#018x}",
+        activation.thread.pc(),
+        unsafe { get_stval() }
+    );
+    activation.thread.registers().dump();
+    handle.kill();
+}
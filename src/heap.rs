@@ -3,11 +3,17 @@ use core::{
     alloc::{AllocError, Allocator, GlobalAlloc, Layout},
     arch::global_asm,
     cmp::max,
+    fmt::Display,
     ptr::{self, slice_from_raw_parts_mut, NonNull},
     sync::atomic::{AtomicPtr, AtomicU16, AtomicUsize, Ordering::Relaxed},
 };
 
-use crate::{data::AtomicBitVec, sync::Mutex};
+use crate::{
+    consts::{LOCK_RANK_PAGE_ALLOCATOR, LOCK_RANK_SLAB_ALLOCATOR},
+    data::AtomicBitVec,
+    println,
+    sync::{Mutex, Once},
+};
 
 extern "C" {
     pub fn get_heap_base() -> *mut u8;
@@ -15,15 +21,58 @@ extern "C" {
 
 global_asm!(include_str!("heap.S"));
 
-const RAM_BASE: *mut u8 = 0x40000000 as *mut u8;
-const RAM_LENGTH: usize = 1024 * 1024 * 1024 * 4;
-const RAM_END: *mut u8 = RAM_BASE.wrapping_add(RAM_LENGTH);
+const RAM_BASE_ADDRESS: usize = 0x40000000;
+const RAM_BASE: *mut u8 = RAM_BASE_ADDRESS as *mut u8;
+const DEFAULT_RAM_LENGTH: usize = 1024 * 1024 * 1024 * 4;
 const PAGE_SIZE: usize = 4096;
+const SUPERPAGE_GRAIN_STEP: usize = 9; // Each page table level covers 512 times as much
+
+// Replaced by the device tree's memory node, if it has one
+static RAM_END: AtomicUsize = AtomicUsize::new(RAM_BASE_ADDRESS + DEFAULT_RAM_LENGTH);
+
+fn ram_end() -> *mut u8 {
+    RAM_END.load(Relaxed) as *mut u8
+}
+
+fn ram_length() -> usize {
+    RAM_END.load(Relaxed) - RAM_BASE as usize
+}
+
+const NO_MISPLACED_RAM: usize = usize::MAX;
+
+// Set when the device tree puts RAM somewhere other than RAM_BASE, which can't be moved, so the
+// warning can wait until the console is usable
+static MISPLACED_RAM_BASE: AtomicUsize = AtomicUsize::new(NO_MISPLACED_RAM);
+
+// Has no effect once the allocators are initialized. RAM starting anywhere but RAM_BASE keeps the
+// built in size, since the range past RAM_BASE may not be RAM at all.
+pub fn set_ram_range(base: u64, size: u64) {
+    if base != RAM_BASE_ADDRESS as u64 {
+        MISPLACED_RAM_BASE.store(base as usize, Relaxed);
+        return;
+    }
+    if size > 0 {
+        RAM_END.store(base.saturating_add(size) as usize, Relaxed);
+    }
+}
 
 struct BumpAllocator {
     offset: AtomicUsize,
 }
 
+// Warn at boot if the page allocator's bookkeeping leaves less than this behind
+const BUMP_WARNING_THRESHOLD: usize = 1024 * 1024;
+
+impl BumpAllocator {
+    fn top(&self) -> *mut u8 {
+        unsafe { get_heap_base().add(self.offset.load(Relaxed)) }
+    }
+
+    fn remaining_bytes(&self) -> usize {
+        (ram_end() as usize).saturating_sub(self.top() as usize)
+    }
+}
+
 unsafe impl Allocator for &BumpAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         unsafe {
@@ -31,15 +80,18 @@ unsafe impl Allocator for &BumpAllocator {
             match self.offset.fetch_update(Relaxed, Relaxed, |mut offset| {
                 let heap_top = heap_base.add(offset);
                 let aligned: *mut u8 = heap_top.add(heap_top.align_offset(layout.align()));
-                if RAM_END.offset_from(aligned) > layout.size() as isize {
-                    offset = aligned.offset_from(heap_base) as usize;
+                if ram_end().offset_from(aligned) > layout.size() as isize {
+                    offset = aligned.add(layout.size()).offset_from(heap_base) as usize;
                     Some(offset)
                 } else {
                     None
                 }
             }) {
                 Ok(prev) => Ok(NonNull::new(slice_from_raw_parts_mut(
-                    heap_base.add(prev),
+                    {
+                        let heap_top = heap_base.add(prev);
+                        heap_top.add(heap_top.align_offset(layout.align()))
+                    },
                     layout.size(),
                 ))
                 .expect("Allocated null pointer!")),
@@ -100,7 +152,7 @@ struct PageFreeList {
 impl PageFreeList {
     fn new(num_pages: usize, grain: usize) -> PageFreeList {
         PageFreeList {
-            available: AtomicBitVec::new_in(num_pages >> grain, &BUMP_ALLOCATOR),
+            available: AtomicBitVec::new_zeroed_in(num_pages >> grain, &BUMP_ALLOCATOR),
             pages: AtomicPtr::default(),
             grain: grain,
         }
@@ -144,16 +196,16 @@ impl PageFreeList {
         }
     }
 
-    fn deallocate_page(&self, page: *mut PageLink) -> Option<*mut PageLink> {
-        self.deallocate_page_exact(self.get_index(page), page)
-    }
-
     fn deallocate_page_from_index(&self, index: usize) -> Option<*mut PageLink> {
         self.deallocate_page_exact(index, self.get_page(index))
     }
 
     #[inline(always)]
     fn deallocate_page_exact(&self, index: usize, page: *mut PageLink) -> Option<*mut PageLink> {
+        // Relinking a page that's already free would corrupt the list, so stop right here instead
+        if self.available.get(index).unwrap_or(false) {
+            panic!("Double free of page {} at grain {}!", index, self.grain);
+        }
         let buddy_index = index ^ 1;
         let lower_index = index & (!1);
         if self.available.get(buddy_index).unwrap_or(false) {
@@ -171,18 +223,28 @@ impl PageFreeList {
 
 struct PageAllocator {
     grained_lists: Vec<PageFreeList, &'static BumpAllocator>,
+    // No grain above this has a free block. Raised on every free, and lowered when split_block
+    // finds nothing, so it may overestimate but never underestimates.
+    max_free_grain: AtomicUsize,
 }
 
 static BUMP_ALLOCATOR: BumpAllocator = BumpAllocator {
     offset: AtomicUsize::new(0),
 };
 
-static PAGE_ALLOCATOR: Mutex<PageAllocator> = Mutex::new(PageAllocator {
-    grained_lists: Vec::new_in(&BUMP_ALLOCATOR),
-});
+static PAGE_ALLOCATOR: Mutex<PageAllocator> = Mutex::new_ranked(
+    PageAllocator {
+        grained_lists: Vec::new_in(&BUMP_ALLOCATOR),
+        max_free_grain: AtomicUsize::new(0),
+    },
+    LOCK_RANK_PAGE_ALLOCATOR,
+);
 
 enum PageAllocationError {
     OutOfMemory,
+    OutOfBounds,
+    InUse,
+    Misaligned,
 }
 
 #[derive(Debug)]
@@ -192,7 +254,7 @@ enum PageDeallocationError {
 
 impl PageAllocator {
     fn init(&mut self) {
-        let num_pages = RAM_LENGTH / PAGE_SIZE;
+        let num_pages = ram_length() / PAGE_SIZE;
         let depth = num_pages.checked_ilog2().expect("System has zero pages!");
         self.grained_lists
             .try_reserve_exact(depth as usize)
@@ -202,32 +264,53 @@ impl PageAllocator {
                 .push(PageFreeList::new(num_pages, grain as usize));
         });
 
-        let bytes_allocated = BUMP_ALLOCATOR.offset.load(Relaxed);
-        let pages_allocated = bytes_allocated.div_ceil(PAGE_SIZE);
-        (pages_allocated + 1..num_pages).for_each(|page_index| {
-            self.deallocate_page_from_index(page_index, 0)
-                .expect("Failed to free pages while initializing page allocator!")
-        });
+        // Everything past the bump allocations is free. Page indices count from RAM_BASE, so this
+        // also keeps the kernel image and whatever sits below it.
+        let heap_end = BUMP_ALLOCATOR.top();
+        let mut page_index =
+            (unsafe { heap_end.offset_from(RAM_BASE) } as usize).div_ceil(PAGE_SIZE);
+
+        // Free the range as the largest aligned blocks that fit instead of page by page. None of
+        // these blocks has a free buddy, so nothing needs to coalesce.
+        while page_index < num_pages {
+            let mut grain = (page_index.trailing_zeros() as usize).min(depth as usize);
+            while page_index + (1 << grain) > num_pages {
+                grain -= 1;
+            }
+            self.deallocate_page_from_index(page_index >> grain, grain)
+                .expect("Failed to free pages while initializing page allocator!");
+            page_index += 1 << grain;
+        }
     }
 
     fn split_block(&self, target_grain: usize) -> Option<*mut PageLink> {
-        self.grained_lists[target_grain + 1..]
+        let max_free_grain = self
+            .max_free_grain
+            .load(Relaxed)
+            .min(self.grained_lists.len() - 1);
+        if max_free_grain <= target_grain {
+            return None;
+        }
+        let found = self.grained_lists[target_grain + 1..=max_free_grain]
             .iter()
             .enumerate()
             .find_map(|(grain_offset, free_list)| {
                 free_list
                     .allocate_page()
                     .map(|page| (target_grain + 1 + grain_offset, page))
-            })
-            .map(|(mut grain, block)| {
-                assert!(grain < self.grained_lists.len());
-                while grain > target_grain {
-                    grain -= 1;
-                    let free_list = self.grained_lists.get(grain).unwrap();
-                    free_list.deallocate_page_from_index(free_list.get_index(block) + 1);
-                }
-                block
-            })
+            });
+        if found.is_none() {
+            self.max_free_grain.store(target_grain, Relaxed);
+        }
+        found.map(|(mut grain, block)| {
+            assert!(grain < self.grained_lists.len());
+            while grain > target_grain {
+                grain -= 1;
+                let free_list = self.grained_lists.get(grain).unwrap();
+                free_list.deallocate_page_from_index(free_list.get_index(block) + 1);
+            }
+            block
+        })
     }
 
     fn allocate_pages(&self, num_pages: usize) -> Result<*mut PageLink, PageAllocationError> {
@@ -245,16 +328,113 @@ impl PageAllocator {
         }
     }
 
+    // Blocks are aligned to their own size relative to RAM_BASE, so a block of at least `align`
+    // bytes is aligned as long as RAM_BASE is. That only holds up to a gigapage, so it's checked.
+    fn allocate_aligned(
+        &self,
+        num_pages: usize,
+        align: usize,
+    ) -> Result<*mut PageLink, PageAllocationError> {
+        let num_pages = num_pages.max(align.div_ceil(PAGE_SIZE));
+        let block = self.allocate_pages(num_pages)?;
+        match block as usize % align {
+            0 => Ok(block),
+            _ => {
+                let mut grain = num_pages.ilog2() as usize;
+                grain = grain + (num_pages > (1 << grain)) as usize;
+                self.deallocate_page(block, grain)
+                    .expect("Failed to return a misaligned block!");
+                Err(PageAllocationError::Misaligned)
+            }
+        }
+    }
+
+    // Level 1 is a 2 MiB megapage and level 2 a 1 GiB gigapage
+    #[allow(dead_code)]
+    fn allocate_superpage(&self, level: u8) -> Option<*mut PageLink> {
+        let grain = SUPERPAGE_GRAIN_STEP * level as usize;
+        self.allocate_aligned(1 << grain, PAGE_SIZE << grain).ok()
+    }
+
+    // The grain of the free block holding the page, if it's free at all
+    fn free_block_grain(&self, page_index: usize) -> Option<usize> {
+        self.grained_lists.iter().position(|free_list| {
+            free_list
+                .available
+                .get(page_index >> free_list.grain)
+                .unwrap_or(false)
+        })
+    }
+
+    // Freed blocks coalesce with their buddies, so a block freed twice may only show up as part of a
+    // larger free block, which the free list's own check can't see
+    fn check_not_free(&self, index: usize, grain: usize) {
+        if let Some(free_grain) = self.free_block_grain(index << grain) {
+            panic!(
+                "Double free of page {} at grain {}, already free at grain {}!",
+                index, grain, free_grain
+            );
+        }
+    }
+
+    // Takes the free block holding the page, then gives back every half of it the page isn't in
+    fn reserve_page(&self, page_index: usize, grain: usize) {
+        let free_list = &self.grained_lists[grain];
+        free_list.allocate_target_page(free_list.get_page(page_index >> grain));
+        for grain in (0..grain).rev() {
+            self.grained_lists[grain].deallocate_page_from_index((page_index >> grain) ^ 1);
+        }
+    }
+
+    // For callers that need specific physical pages. Nothing is reserved unless every page is free.
+    #[allow(dead_code)]
+    fn allocate_at(
+        &self,
+        physical_address: usize,
+        num_pages: usize,
+    ) -> Result<(), PageAllocationError> {
+        let first_page = match physical_address.checked_sub(RAM_BASE_ADDRESS) {
+            Some(offset) if offset % PAGE_SIZE == 0 => offset / PAGE_SIZE,
+            _ => return Err(PageAllocationError::OutOfBounds),
+        };
+        let pages = first_page..first_page + num_pages;
+        if pages.end > ram_length() / PAGE_SIZE {
+            return Err(PageAllocationError::OutOfBounds);
+        }
+        if pages
+            .clone()
+            .any(|page_index| self.free_block_grain(page_index).is_none())
+        {
+            return Err(PageAllocationError::InUse);
+        }
+        // Reserving one page only ever gives back pages around it, so the rest stay free
+        for page_index in pages {
+            let grain = self
+                .free_block_grain(page_index)
+                .expect("Page was taken while reserving a range!");
+            self.reserve_page(page_index, grain);
+        }
+        Ok(())
+    }
+
+    // Anything handed to userspace must come from here, so it can't see a previous tenant's data
+    fn allocate_zeroed(
+        &self,
+        num_pages: usize,
+        align: usize,
+    ) -> Result<*mut PageLink, PageAllocationError> {
+        let block = self.allocate_aligned(num_pages, align)?;
+        unsafe { (block as *mut u8).write_bytes(0, num_pages * PAGE_SIZE) };
+        Ok(block)
+    }
+
     fn deallocate_page(
         &self,
         page: *mut PageLink,
         grain: usize,
     ) -> Result<(), PageDeallocationError> {
         match self.grained_lists.get(grain) {
-            Some(free_list) => match free_list.deallocate_page(page) {
-                Some(coalesced_block) => self.deallocate_page(coalesced_block, grain + 1),
-                None => Ok(()),
-            },
+            Some(free_list) => self.deallocate_page_from_index(free_list.get_index(page), grain),
             None => Err(PageDeallocationError::OutOfBounds),
         }
     }
@@ -265,14 +445,37 @@ impl PageAllocator {
         grain: usize,
     ) -> Result<(), PageDeallocationError> {
         match self.grained_lists.get(grain) {
-            Some(free_list) => match free_list.deallocate_page_from_index(index) {
-                Some(coalesced_block) => self.deallocate_page(coalesced_block, grain + 1),
-                None => Ok(()),
-            },
+            Some(free_list) => {
+                self.check_not_free(index, grain);
+                match free_list.deallocate_page_from_index(index) {
+                    Some(coalesced_block) => self.deallocate_page(coalesced_block, grain + 1),
+                    None => {
+                        self.max_free_grain.fetch_max(grain, Relaxed);
+                        Ok(())
+                    }
+                }
+            }
             None => Err(PageDeallocationError::OutOfBounds),
         }
     }
 
+    fn free_pages(&self) -> usize {
+        self.grained_lists
+            .iter()
+            .map(|free_list| free_list.available.iter_set().count() << free_list.grain)
+            .sum()
+    }
+
+    fn print_free_lists(&self) {
+        for free_list in self.grained_lists.iter() {
+            let blocks = free_list.available.iter_set().count();
+            if blocks > 0 {
+                println!("  grain {:>2}: {} free blocks", free_list.grain, blocks);
+            }
+        }
+    }
+
+    // Sized up to the alignment too, so the block's grain is enough to align it
     #[inline(always)]
     fn get_num_pages(layout: Layout) -> usize {
         layout.size().max(layout.align()).div_ceil(PAGE_SIZE)
@@ -282,7 +485,25 @@ impl PageAllocator {
 unsafe impl Allocator for Mutex<PageAllocator> {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         let num_pages = PageAllocator::get_num_pages(layout);
-        match self.lock_blocking().allocate_pages(num_pages) {
+        match self
+            .lock_blocking()
+            .allocate_aligned(num_pages, layout.align())
+        {
+            Ok(block) => Ok(NonNull::new(slice_from_raw_parts_mut(
+                block as *mut u8,
+                num_pages * PAGE_SIZE,
+            ))
+            .expect("Allocated null pointer")),
+            Err(_) => Err(AllocError),
+        }
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let num_pages = PageAllocator::get_num_pages(layout);
+        match self
+            .lock_blocking()
+            .allocate_zeroed(num_pages, layout.align())
+        {
             Ok(block) => Ok(NonNull::new(slice_from_raw_parts_mut(
                 block as *mut u8,
                 num_pages * PAGE_SIZE,
@@ -341,10 +562,72 @@ struct SlabAllocator {
     headers: Vec<SlabHeader, &'static Mutex<PageAllocator>>,
 }
 
+const MAX_SLAB_STATS_SIZES: usize = 8;
+
+#[derive(Clone, Copy, Default)]
+pub struct SlabSizeStats {
+    pub slot_bytes: usize,
+    pub slots: usize,
+    pub in_use: usize,
+}
+
+pub struct SlabStats {
+    pub headers: usize,
+    pub slots: usize,
+    pub in_use: usize,
+    pub sizes: [SlabSizeStats; MAX_SLAB_STATS_SIZES],
+    pub num_sizes: usize,
+}
+
 impl SlabAllocator {
     fn get_slot_size(layout: Layout) -> u16 {
         max(layout.size(), layout.align()).div_ceil(size_of::<FreeLink>()) as u16
     }
+
+    fn stats(&self) -> SlabStats {
+        let mut stats = SlabStats {
+            headers: self.headers.len(),
+            slots: 0,
+            in_use: 0,
+            sizes: [SlabSizeStats::default(); MAX_SLAB_STATS_SIZES],
+            num_sizes: 0,
+        };
+        for header in &self.headers {
+            let slots = header.slot_count();
+            stats.slots += slots;
+            stats.in_use += header.in_use as usize;
+            if let Some(size_stats) = stats.sizes.get_mut(stats.num_sizes) {
+                *size_stats = SlabSizeStats {
+                    slot_bytes: header.slot_size as usize * size_of::<FreeLink>(),
+                    slots,
+                    in_use: header.in_use as usize,
+                };
+                stats.num_sizes += 1;
+            }
+        }
+        stats
+    }
+}
+
+impl Display for SlabStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(
+            f,
+            "{} slab headers, {}/{} slots in use",
+            self.headers, self.in_use, self.slots
+        )?;
+        for size_stats in &self.sizes[..self.num_sizes] {
+            writeln!(
+                f,
+                "  {:>5} byte slots: {}/{} in use",
+                size_stats.slot_bytes, size_stats.in_use, size_stats.slots
+            )?;
+        }
+        if self.headers > self.num_sizes {
+            writeln!(f, "  ({} more slot sizes)", self.headers - self.num_sizes)?;
+        }
+        Ok(())
+    }
 }
 
 impl SlabHeader {
@@ -453,6 +736,10 @@ impl SlabHeader {
         self.deallocate_at(link_offset as u16);
     }
 
+    fn slot_count(&self) -> usize {
+        self.page_memory.len().div_ceil(self.slot_size as usize)
+    }
+
     fn _owns(&self, ptr: *mut u8) -> bool {
         self.page_memory
             .as_ptr_range()
@@ -504,10 +791,146 @@ unsafe impl GlobalAlloc for Mutex<SlabAllocator> {
 }
 
 #[global_allocator]
-static SLAB_ALLOCATOR: Mutex<SlabAllocator> = Mutex::new(SlabAllocator {
-    headers: Vec::new_in(&PAGE_ALLOCATOR),
-});
+static SLAB_ALLOCATOR: Mutex<SlabAllocator> = Mutex::new_ranked(
+    SlabAllocator {
+        headers: Vec::new_in(&PAGE_ALLOCATOR),
+    },
+    LOCK_RANK_SLAB_ALLOCATOR,
+);
+
+pub fn is_ram_range(base: u64, len: u64) -> bool {
+    match base.checked_add(len) {
+        Some(end) => base >= RAM_BASE as u64 && end <= ram_end() as u64,
+        None => false,
+    }
+}
+
+pub fn slab_stats() -> SlabStats {
+    SLAB_ALLOCATOR.lock_blocking().stats()
+}
+
+#[cfg(feature = "boot_selftest")]
+pub fn free_pages() -> usize {
+    PAGE_ALLOCATOR.lock_blocking().free_pages()
+}
+
+// For the allocation error handler, which usually runs with the slab lock held, since the slab
+// allocator ran out taking a page for a new header. Checks is_held first, since the lock rank
+// checker would panic on our own held lock before lock could fail.
+pub fn try_slab_stats() -> Option<SlabStats> {
+    if SLAB_ALLOCATOR.is_held() {
+        return None;
+    }
+    SLAB_ALLOCATOR.lock().ok().map(|slab| slab.stats())
+}
+
+pub fn try_free_pages() -> Option<usize> {
+    if PAGE_ALLOCATOR.is_held() {
+        return None;
+    }
+    PAGE_ALLOCATOR.lock().ok().map(|pages| pages.free_pages())
+}
+
+pub fn print_page_free_lists() {
+    PAGE_ALLOCATOR.lock_blocking().print_free_lists()
+}
+
+// Takes up to `count` single pages, then gives back every other one, leaving a checkerboard that
+// can't coalesce. The rest are kept for good. Returns how many pages were kept.
+pub fn fragment_pages(count: usize) -> usize {
+    // Reserved before taking the lock, since reserving takes it too
+    let mut pages = Vec::new_in(&PAGE_ALLOCATOR);
+    if pages.try_reserve_exact(count).is_err() {
+        return 0;
+    }
+    let allocator = PAGE_ALLOCATOR.lock_blocking();
+    while pages.len() < count {
+        match allocator.allocate_pages(1) {
+            Ok(page) => pages.push(page),
+            Err(_) => break,
+        }
+    }
+    for &page in pages.iter().skip(1).step_by(2) {
+        allocator
+            .deallocate_page(page, 0)
+            .expect("Failed to free a page while fragmenting!");
+    }
+    drop(allocator);
+    pages.len().div_ceil(2)
+}
+
+// Frees a two page block a page at a time, so the halves coalesce. The first page's own bit stays
+// clear, so only the check against every grain would catch it being freed again.
+#[cfg(feature = "boot_selftest")]
+pub fn check_page_double_free() -> bool {
+    let allocator = PAGE_ALLOCATOR.lock_blocking();
+    let before = allocator.free_pages();
+    let Ok(block) = allocator.allocate_pages(2) else {
+        return false;
+    };
+    let page_index = allocator.grained_lists[0].get_index(block);
+    let freed = allocator.deallocate_page(block, 0).is_ok()
+        && allocator
+            .deallocate_page(unsafe { block.add(1) }, 0)
+            .is_ok();
+    let own_bit_clear = allocator.grained_lists[0].available.get(page_index) == Some(false);
+    let caught = allocator
+        .free_block_grain(page_index)
+        .is_some_and(|grain| grain > 0);
+    freed && own_bit_clear && caught && allocator.free_pages() == before
+}
+
+// Takes a 64 KiB aligned page and gives it back, checking the free page count is restored
+#[cfg(feature = "boot_selftest")]
+pub fn check_page_round_trip() -> bool {
+    let layout = Layout::from_size_align(PAGE_SIZE, 16 * PAGE_SIZE).unwrap();
+    let before = free_pages();
+    let Ok(block) = PAGE_ALLOCATOR.allocate(layout) else {
+        return false;
+    };
+    let aligned = block.as_mut_ptr() as usize % layout.align() == 0;
+    let taken = free_pages() < before;
+    unsafe {
+        block.as_mut_ptr().write_bytes(0xA5, layout.size());
+        PAGE_ALLOCATOR.deallocate(block.as_non_null_ptr(), layout);
+    }
+    aligned && taken && free_pages() == before
+}
+
+#[cfg(feature = "boot_selftest")]
+pub fn check_slab_round_trip() -> bool {
+    let before = slab_stats().in_use;
+    let object = Box::new([0x5A5A_5A5Au32; 8]);
+    let allocated =
+        slab_stats().in_use == before + 1 && object.iter().all(|&word| word == 0x5A5A_5A5A);
+    drop(object);
+    allocated && slab_stats().in_use == before
+}
+
+static ALLOCATORS_INITIALIZED: Once = Once::new();
 
+// Safe to call from every hart, only the first call does anything
 pub fn init_allocators() {
-    PAGE_ALLOCATOR.lock_blocking_mut().init()
+    ALLOCATORS_INITIALIZED.call_once(|| {
+        let misplaced_base = MISPLACED_RAM_BASE.load(Relaxed);
+        if misplaced_base != NO_MISPLACED_RAM {
+            println!(
+                "Warning: device tree has RAM at {:#x} rather than {:#x}, using the built in size",
+                misplaced_base, RAM_BASE_ADDRESS
+            );
+        }
+        PAGE_ALLOCATOR.lock_blocking_mut().init();
+        let remaining = BUMP_ALLOCATOR.remaining_bytes();
+        if remaining < BUMP_WARNING_THRESHOLD {
+            println!(
+                "Warning: only {} bytes left past the page allocator's bookkeeping",
+                remaining
+            );
+        }
+    })
+}
+
+// (top, bytes left) of the boot time bump allocator
+pub fn bump_allocator_state() -> (*const u8, usize) {
+    (BUMP_ALLOCATOR.top(), BUMP_ALLOCATOR.remaining_bytes())
 }
@@ -1,15 +1,46 @@
-use crate::thread::{ThreadActivationResult, ThreadHandle};
+use crate::{
+    ipi::handle_ipis,
+    println,
+    smp::current_hart_id,
+    thread::{ResumeMode, ThreadActivationResult, ThreadHandle},
+    time::{handle_timer_interrupt, QUANTUM_TIMER, WATCHDOG_TIMER},
+    trap_stats::record_interrupt,
+};
 
 pub const IS_INTERRUPT_MASK: u64 = 0x80000000_00000000;
 pub const SOFTWARE_INTERRUPT: u64 = 1;
 pub const TIMER_INTERRUPT: u64 = 5;
 pub const EXTERNAL_INTERRUPT: u64 = 9;
 
+pub fn interrupt_name(code: u64) -> &'static str {
+    match code {
+        SOFTWARE_INTERRUPT => "Software Interrupt",
+        TIMER_INTERRUPT => "Timer Interrupt",
+        EXTERNAL_INTERRUPT => "External Interrupt",
+        _ => "Unknown Interrupt",
+    }
+}
+
 pub fn handle_interrupt(activation: &ThreadActivationResult, handle: &ThreadHandle) {
     let reason: u64 = activation.cause ^ IS_INTERRUPT_MASK;
+    record_interrupt(reason);
     match reason {
-        SOFTWARE_INTERRUPT => handle.kill(), // No idea how to handle this for now
-        TIMER_INTERRUPT => handle.resolve_interrupt_or_kill(false), // Do nothing, just need to reschedule
+        SOFTWARE_INTERRUPT => {
+            handle_ipis(current_hart_id());
+            handle.resolve_interrupt_or_kill(ResumeMode::Retry)
+        }
+        TIMER_INTERRUPT => {
+            let mut preempted = false;
+            handle_timer_interrupt(|callback_id| match callback_id {
+                QUANTUM_TIMER => preempted = true, // Nothing to do but reschedule
+                WATCHDOG_TIMER => (), // A thread is running, so the scheduler isn't stuck
+                unknown => println!("Expired timer has unknown callback: {}", unknown),
+            });
+            if preempted {
+                handle.note_preemption();
+            }
+            handle.resolve_interrupt_or_kill(ResumeMode::Retry)
+        }
         EXTERNAL_INTERRUPT => handle.kill(), // No idea how to handle this for now
         _ => panic!("Unknown interrupt encountered: {}", reason),
     }
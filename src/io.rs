@@ -1,7 +1,89 @@
+use crate::{consts::MAX_IO_ATTEMPTS, sync::retry_with_backoff};
+
 pub trait Readable<T> {
     fn read(&self) -> Option<T>;
+
+    #[allow(dead_code)]
+    fn read_blocking(&self) -> T {
+        let mut value = None;
+        retry_with_backoff(MAX_IO_ATTEMPTS, || {
+            value = self.read();
+            value.is_some()
+        });
+        match value {
+            Some(v) => v,
+            None => panic!("Took too long to read!"),
+        }
+    }
 }
 
 pub trait Writable<T> {
     fn write(&self, v: T) -> Result<(), ()>;
+
+    fn write_blocking(&self, v: T)
+    where
+        T: Copy,
+    {
+        if !retry_with_backoff(MAX_IO_ATTEMPTS, || self.write(v).is_ok()) {
+            panic!("Took too long to write!");
+        }
+    }
+}
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const PRINTABLE: core::ops::RangeInclusive<u8> = b' '..=b'~';
+
+// Buffers bytes from `source` until a line is terminated by a carriage return or newline
+pub struct LineReader<R: Readable<u8>, const CAPACITY: usize> {
+    source: R,
+    buffer: [u8; CAPACITY],
+    len: usize,
+    line_returned: bool,
+    last_was_cr: bool,
+}
+
+impl<R: Readable<u8>, const CAPACITY: usize> LineReader<R, CAPACITY> {
+    pub const fn new(source: R) -> Self {
+        Self {
+            source,
+            buffer: [0; CAPACITY],
+            len: 0,
+            line_returned: false,
+            last_was_cr: false,
+        }
+    }
+
+    // Consumes all available input. `echo` is handed whatever should be displayed in response.
+    // Returns the line once one is complete; it stays valid until the next poll.
+    pub fn poll_line(&mut self, mut echo: impl FnMut(&[u8])) -> Option<&str> {
+        if core::mem::replace(&mut self.line_returned, false) {
+            self.len = 0;
+        }
+        while let Some(byte) = self.source.read() {
+            let after_cr = core::mem::replace(&mut self.last_was_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => (), // Second half of a CRLF
+                b'\r' | b'\n' => {
+                    echo(b"\r\n");
+                    self.line_returned = true;
+                    // Only printable ASCII is ever buffered, so this is always valid
+                    return core::str::from_utf8(&self.buffer[..self.len]).ok();
+                }
+                BACKSPACE | DELETE => {
+                    if self.len > 0 {
+                        self.len -= 1;
+                        echo(b"\x08 \x08");
+                    }
+                }
+                _ if PRINTABLE.contains(&byte) && self.len < CAPACITY => {
+                    self.buffer[self.len] = byte;
+                    self.len += 1;
+                    echo(&[byte]);
+                }
+                _ => (), // Buffer is full, or the byte isn't something we can display
+            }
+        }
+        None
+    }
 }
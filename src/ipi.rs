@@ -0,0 +1,58 @@
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{consts::MAX_HARTS, smp::sbi_send_ipi};
+
+// Why another hart interrupted us. Several can be pending at once, so each is a bit in the mailbox.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum IpiReason {
+    Fence,      // Address translations changed, flush the TLB
+    Reschedule, // Stop running the current thread and go back to the scheduler
+}
+
+impl IpiReason {
+    const ALL: [IpiReason; 2] = [IpiReason::Fence, IpiReason::Reschedule];
+
+    fn bit(self) -> u64 {
+        1 << self as u64
+    }
+}
+
+static MAILBOXES: [AtomicU64; MAX_HARTS] = [const { AtomicU64::new(0) }; MAX_HARTS];
+
+// Returns false if the target isn't a hart SBI could interrupt
+#[allow(dead_code)]
+pub fn send_ipi(target_hart: usize, reason: IpiReason) -> bool {
+    if target_hart >= MAX_HARTS {
+        return false;
+    }
+    // Posted first, so the reason is there by the time the target looks
+    MAILBOXES[target_hart].fetch_or(reason.bit(), Ordering::Release);
+    unsafe { sbi_send_ipi(1 << target_hart, 0) == 0 }
+}
+
+// Clears the pending software interrupt and acts on everything in this hart's mailbox
+pub fn handle_ipis(hart_id: usize) {
+    // Cleared before emptying the mailbox, so anything posted after it raises the interrupt again
+    unsafe { clear_ipi() };
+    let pending = MAILBOXES[hart_id].swap(0, Ordering::Acquire);
+    for reason in IpiReason::ALL {
+        if pending & reason.bit() == 0 {
+            continue;
+        }
+        match reason {
+            IpiReason::Fence => unsafe { fence_vma() },
+            IpiReason::Reschedule => (), // Taking the interrupt already ended the activation
+        }
+    }
+}
+
+extern "C" {
+    fn clear_ipi();
+    fn fence_vma();
+}
+
+global_asm!(include_str!("ipi.S"));
@@ -1,5 +1,6 @@
 #![no_main]
 #![no_std]
+#![feature(alloc_error_handler)]
 #![feature(allocator_api)]
 #![feature(const_box)]
 #![feature(error_generic_member_access)]
@@ -11,55 +12,94 @@ mod consts;
 mod context;
 mod data;
 mod debug;
+mod dtb;
 mod exception;
 mod heap;
 mod interrupt;
 mod io;
+mod ipi;
+mod pipe;
 mod process;
 mod reg;
 mod resource;
+#[cfg(feature = "boot_selftest")]
+mod selftest;
+mod smp;
 mod sync;
 mod syscall;
 mod thread;
 mod time;
+mod trap_stats;
 mod uart;
 
-use consts::MAX_PROCESSES;
+use consts::{
+    CONSOLE_BUFFER_SIZE, CONSOLE_PROMPT, LOCK_RANK_SCHEDULER, MAX_PROCESSES, WATCHDOG_TIMEOUT_MS,
+};
 use context::init_context;
+use core::alloc::Layout;
 use core::arch::{asm, global_asm};
+use core::hint::spin_loop;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::unreachable;
-use debug::test_context;
+use debug::{print_backtrace, test_context};
 use exception::{handle_exception, init_exception_handler};
-use heap::init_allocators;
-use interrupt::{handle_interrupt, IS_INTERRUPT_MASK};
-use io::Writable;
+use heap::{
+    bump_allocator_state, fragment_pages, init_allocators, print_page_free_lists, slab_stats,
+    try_free_pages, try_slab_stats,
+};
+use interrupt::handle_interrupt;
+use io::{LineReader, Writable};
+use ipi::handle_ipis;
 use process::ProcessControlBlock;
 use resource::ResourceManager;
-use uart::{UartHandler, UART0_BASE};
+use smp::{current_hart_id, mark_hart_started, start_secondary_harts};
+use sync::Mutex;
+use thread::{
+    clear_current_thread, ThreadActivationError, ThreadActivationResult, ThreadHandle,
+    LAST_ACTIVATION_TIME,
+};
+use time::{get_time, handle_timer_interrupt, ms_to_ticks, rearm_timer_deadline, WATCHDOG_TIMER};
+use trap_stats::print_trap_stats;
+use uart::{uart0_base, UartHandler};
 
-use crate::io::Readable;
 extern crate alloc;
 
 global_asm!(include_str!("consts.S"));
 global_asm!(include_str!("boot.S"));
 
-static mut BOOTLOADER_RETURN_ADDRESS: i64 = 0;
+// Where the bootloader called _start from, so a panic can hand control back to it. Only the boot
+// hart came from there, secondaries are started through SBI.
+static mut BOOTLOADER_RETURN_ADDRESS: u64 = 0;
+static mut BOOTLOADER_STACK_POINTER: u64 = 0;
+static BOOT_HART_ID: AtomicUsize = AtomicUsize::new(usize::MAX);
 static mut PROCESS_TABLE: ResourceManager<Option<ProcessControlBlock>, MAX_PROCESSES> =
     ResourceManager::new([const { None }; MAX_PROCESSES]);
+// Held while choosing a thread, or adding and removing threads from PROCESS_TABLE
+static SCHEDULER_LOCK: Mutex<()> = Mutex::new_ranked((), LOCK_RANK_SCHEDULER);
+// Whether typed characters are echoed back, line breaks are always echoed
+static CONSOLE_ECHO: AtomicBool = AtomicBool::new(true);
 
 #[no_mangle]
 #[allow(dead_code)]
-extern "C" fn kmain(hart_id: u64, _dtb: *const u8) -> ! {
+extern "C" fn kmain(
+    hart_id: u64,
+    dtb: *const u8,
+    bootloader_return_address: u64,
+    bootloader_stack_pointer: u64,
+) -> ! {
     unsafe {
-        asm!(
-            "mv {0}, ra",
-            out(reg) BOOTLOADER_RETURN_ADDRESS,
-        );
+        BOOTLOADER_RETURN_ADDRESS = bootloader_return_address;
+        BOOTLOADER_STACK_POINTER = bootloader_stack_pointer;
     }
-    let console = UartHandler::new(UART0_BASE);
+    BOOT_HART_ID.store(hart_id as usize, Ordering::Release);
+    let found_device_tree = unsafe { dtb::init(dtb) };
+    let console = UartHandler::new(uart0_base());
     println!("Welcome to EepyOS!");
     println!("Hello from core: {}", hart_id);
+    if !found_device_tree {
+        println!("No device tree found, using built in defaults");
+    }
 
     unsafe {
         init_exception_handler();
@@ -97,45 +137,247 @@ extern "C" fn kmain(hart_id: u64, _dtb: *const u8) -> ! {
             .expect("Failed to spawn fourth process");
     }
 
+    #[cfg(feature = "boot_selftest")]
+    selftest::run(hart_id);
+
+    let harts = start_secondary_harts(hart_id);
+    println!("{} harts online", harts);
+
+    // The boot hart serves the console between threads, so step works with threads live
+    let mut reader: LineReader<UartHandler, CONSOLE_BUFFER_SIZE> =
+        LineReader::new(UartHandler::new(uart0_base()));
+    let mut started_console = false;
+    let mut step_index = 0;
+    let mut poll_console = || {
+        if !started_console {
+            println!("Starting console...");
+            print!("{}", CONSOLE_PROMPT);
+            started_console = true;
+        }
+        let echo = |bytes: &[u8]| {
+            if CONSOLE_ECHO.load(Ordering::Relaxed) || bytes == b"\r\n" {
+                bytes.iter().for_each(|&byte| console.write_blocking(byte));
+            }
+        };
+        let Some(line) = reader.poll_line(echo) else {
+            return;
+        };
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        let usage = CONSOLE_COMMANDS
+            .iter()
+            .find(|(name, _)| *name == command)
+            .map(|(_, usage)| *usage);
+        match (command, usage) {
+            ("", _) => (),
+            (_, None) => match closest_command(command) {
+                Some(suggestion) => {
+                    println!("Unknown command: {}, did you mean {}?", command, suggestion)
+                }
+                None => println!("Unknown command: {}", command),
+            },
+            ("help", _) => CONSOLE_COMMANDS
+                .iter()
+                .for_each(|(_, usage)| println!("  {}", usage)),
+            ("step", _) => step_scheduler(hart_id, &mut step_index),
+            ("trapstats", _) => print_trap_stats(),
+            ("slabstats", _) => print!("{}", slab_stats()),
+            ("bumpa", _) => {
+                let (top, remaining) = bump_allocator_state();
+                println!("Bump allocator at {:p}, {} bytes left", top, remaining);
+            }
+            ("frag", Some(usage)) => match args.parse() {
+                Ok(count) => {
+                    let kept = fragment_pages(count);
+                    println!("Kept {} pages, free lists are now:", kept);
+                    print_page_free_lists();
+                }
+                Err(_) => println!("Usage: {}", usage),
+            },
+            ("setenv", Some(usage)) => match args {
+                "echo on" => CONSOLE_ECHO.store(true, Ordering::Relaxed),
+                "echo off" => CONSOLE_ECHO.store(false, Ordering::Relaxed),
+                _ => println!("Usage: {}", usage),
+            },
+            (command, _) => println!("Command {} is listed but has no handler", command),
+        }
+        print!("{}", CONSOLE_PROMPT);
+    };
+
+    run_scheduler(hart_id, |_| poll_console());
+    loop {
+        poll_console();
+    }
+}
+
+#[no_mangle]
+#[allow(dead_code)]
+extern "C" fn kmain_secondary(hart_id: u64, _opaque: u64) -> ! {
+    unsafe {
+        init_exception_handler();
+        init_context();
+    }
+    // Already done by the boot hart, this just makes sure of it
+    init_allocators();
+    mark_hart_started();
+    println!("Hello from core: {}", hart_id);
+
+    // An IPI that arrives while idle stays pending, and wfi won't wait again until it's handled
+    let wait_for_interrupt = || {
+        unsafe { asm!("wfi") };
+        handle_ipis(hart_id as usize);
+    };
+    run_scheduler(hart_id, |idle| {
+        if idle {
+            wait_for_interrupt();
+        }
+    });
+
+    loop {
+        wait_for_interrupt();
+    }
+}
+
+// Calls between_threads after every pass, with whether there was nothing at all to run, since a
+// thread may still be spawned later. Only returns if the watchdog gives up on the scheduler.
+fn run_scheduler(hart_id: u64, mut between_threads: impl FnMut(bool)) {
+    let mut last_scheduled_index = 0;
+    LAST_ACTIVATION_TIME.fetch_max(unsafe { get_time() }, Ordering::Relaxed);
     loop {
         unsafe {
+            // Fires if no hart activates a thread for WATCHDOG_TIMEOUT_MS
+            let watchdog_deadline = LAST_ACTIVATION_TIME
+                .load(Ordering::Relaxed)
+                .saturating_add(ms_to_ticks(WATCHDOG_TIMEOUT_MS));
+            if let Err(err) = rearm_timer_deadline(watchdog_deadline, WATCHDOG_TIMER) {
+                println!("Failed to arm the watchdog: {}", err);
+            }
+
             // TODO: Track number of "living" threads per process
-            let scheduled_thread = match PROCESS_TABLE.choose_next_thread() {
+            // Holding a handle keeps every other hart from choosing the same thread
+            let chosen_thread = SCHEDULER_LOCK
+                .with(|_| PROCESS_TABLE.choose_next_thread(&mut last_scheduled_index));
+            let scheduled_thread = match chosen_thread {
+                None if PROCESS_TABLE.has_live_threads() => {
+                    // Everything left is running on another hart. The kernel runs with interrupts
+                    // masked, so the watchdog has to be polled here.
+                    let mut stalled = false;
+                    handle_timer_interrupt(|callback_id| stalled |= callback_id == WATCHDOG_TIMER);
+                    if stalled {
+                        println!(
+                            "Watchdog: no thread activated in {} ms, core {} leaving the scheduler",
+                            WATCHDOG_TIMEOUT_MS, hart_id
+                        );
+                        PROCESS_TABLE.print_thread_states();
+                        break;
+                    }
+                    spin_loop();
+                    between_threads(false);
+                    continue;
+                }
                 None => {
-                    println!("Out of threads to schedule, starting echo loop...");
-                    break;
+                    // Having nothing to run isn't a stall, so keep the watchdog quiet
+                    LAST_ACTIVATION_TIME.fetch_max(get_time(), Ordering::Relaxed);
+                    between_threads(true);
+                    continue;
                 }
                 Some(chosen_thread) => chosen_thread,
             };
 
-            let run_result = match scheduled_thread.activate(hart_id) {
-                Ok(result) => result,
-                Err(msg) => {
-                    println!("Error trying to run thread: {}", msg);
-                    break;
-                }
-            };
-
-            if run_result.cause & IS_INTERRUPT_MASK > 0 {
-                handle_interrupt(&run_result, &scheduled_thread);
-            } else {
-                handle_exception(&run_result, &scheduled_thread);
+            if let Err(msg) = run_thread(hart_id, &scheduled_thread, |_| {}) {
+                println!("Error trying to run thread: {}", msg);
+                break;
             }
+            // Only once the handle is dropped, so a console step can choose this thread too
+            drop(scheduled_thread);
+            between_threads(false);
         }
     }
+}
 
-    loop {
-        if let Some(inp) = console.read() {
-            match console.write(inp) {
-                Ok(()) => (),
-                Err(()) => {
-                    let mut rval = console.read();
-                    while rval.is_some() {
-                        rval = console.read();
-                    }
-                }
+// Runs the thread until it traps, shows the trap to on_trap, then handles it
+fn run_thread(
+    hart_id: u64,
+    scheduled_thread: &ThreadHandle,
+    on_trap: impl FnOnce(&ThreadActivationResult),
+) -> Result<(), ThreadActivationError> {
+    let run_result = scheduled_thread.activate(hart_id)?;
+    on_trap(&run_result);
+    if scheduled_thread.kill_if_pending() {
+        // Its process exited while it was running, so whatever it trapped for is moot
+    } else if run_result.is_interrupt() {
+        handle_interrupt(&run_result, scheduled_thread);
+    } else {
+        handle_exception(&run_result, scheduled_thread);
+    }
+    clear_current_thread(hart_id);
+    Ok(())
+}
+
+// Every console command as (name, usage). Both help and dispatch go by this, so a command missing
+// here can't be run.
+const CONSOLE_COMMANDS: &[(&str, &str)] = &[
+    ("help", "help                   list every command"),
+    ("step", "step                   run the next thread once"),
+    ("trapstats", "trapstats              count traps by cause"),
+    (
+        "slabstats",
+        "slabstats              show slab allocator usage",
+    ),
+    (
+        "bumpa",
+        "bumpa                  show the boot bump allocator",
+    ),
+    (
+        "frag",
+        "frag <pages>           fragment page memory on purpose",
+    ),
+    (
+        "setenv",
+        "setenv echo <on|off>   turn typed character echo on or off",
+    ),
+];
+const MAX_COMMAND_NAME_LEN: usize = 16;
+
+fn closest_command(typed: &str) -> Option<&'static str> {
+    CONSOLE_COMMANDS
+        .iter()
+        .map(|(name, _)| (*name, edit_distance(typed.as_bytes(), name.as_bytes())))
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(name, _)| name)
+}
+
+// Levenshtein distance, keeping one row for `known`, which must be a command name
+fn edit_distance(typed: &[u8], known: &[u8]) -> usize {
+    assert!(known.len() <= MAX_COMMAND_NAME_LEN);
+    let mut row = [0; MAX_COMMAND_NAME_LEN + 1];
+    row.iter_mut()
+        .enumerate()
+        .for_each(|(index, cell)| *cell = index);
+    for (typed_index, &typed_byte) in typed.iter().enumerate() {
+        // row[j] is the distance from typed[..typed_index] to known[..j], diagonal the old row[j]
+        let mut diagonal = row[0];
+        row[0] = typed_index + 1;
+        for (known_index, &known_byte) in known.iter().enumerate() {
+            let substitution = diagonal + (typed_byte != known_byte) as usize;
+            diagonal = row[known_index + 1];
+            row[known_index + 1] = substitution.min(row[known_index] + 1).min(diagonal + 1);
+        }
+    }
+    row[known.len()]
+}
+
+// The console's step command: picks a thread the way the scheduler would and runs it once
+fn step_scheduler(hart_id: u64, last_scheduled_index: &mut usize) {
+    let chosen_thread =
+        SCHEDULER_LOCK.with(|_| unsafe { PROCESS_TABLE.choose_next_thread(last_scheduled_index) });
+    match chosen_thread {
+        Some(thread) => {
+            if let Err(msg) = run_thread(hart_id, &thread, |trap| println!("{}", trap)) {
+                println!("Error trying to run thread: {}", msg);
             }
         }
+        None => println!("No thread is ready to run"),
     }
 }
 
@@ -157,16 +399,45 @@ extern "C" fn test3() -> u64 {
     loop {}
 }
 
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    println!(
+        "Failed to allocate {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+    match try_slab_stats() {
+        Some(stats) => print!("{}", stats),
+        None => println!("slab locked"),
+    }
+    match try_free_pages() {
+        Some(pages) => println!("{} pages free", pages),
+        None => println!("page allocator locked"),
+    }
+    panic!("Out of memory!");
+}
+
 #[no_mangle]
 #[panic_handler]
 unsafe fn panic(info: &PanicInfo) -> ! {
     if let Some(msg) = info.message().as_str() {
         println!("Kernel panic: {}", msg);
     }
+    print_backtrace();
+    // Every other hart has nowhere to return to, and the boot hart may still be using the
+    // bootloader's stack, so it parks
+    if current_hart_id() != BOOT_HART_ID.load(Ordering::Acquire) {
+        loop {
+            asm!("wfi");
+        }
+    }
+    // The panic may be on any stack, so go back to the bootloader's before returning to it
     asm!(
-        "mv ra, {0}",
+        "mv sp, t1",
+        "mv ra, t0",
         "ret",
-        in(reg) BOOTLOADER_RETURN_ADDRESS,
+        in("t0") BOOTLOADER_RETURN_ADDRESS,
+        in("t1") BOOTLOADER_STACK_POINTER,
     );
     unreachable!();
 }
@@ -0,0 +1,180 @@
+use core::{error::Error, fmt::Display};
+
+use crate::{
+    consts::{LOCK_RANK_PIPES, MAX_PIPES, PIPE_CAPACITY},
+    data::Channel,
+    resource::{Resource, ResourceClaimError, ResourceId, ResourceManager},
+    sync::Mutex,
+    thread::{InvalidTransition, ThreadHandle, WaitReason},
+    PROCESS_TABLE, SCHEDULER_LOCK,
+};
+
+#[derive(Clone, Copy)]
+pub enum PipeEnd {
+    Read,
+    Write,
+}
+
+pub struct Pipe {
+    buffer: Channel<u8, PIPE_CAPACITY>,
+    read_open: bool,
+    write_open: bool,
+}
+
+// How far a read or write got. A blocked caller retries once it is woken.
+pub enum PipeTransfer {
+    Done(usize),
+    Blocked,
+}
+
+#[derive(Debug)]
+pub enum PipeError {
+    InvalidPipe,
+    EndClosed,
+    NoSpaceAvailable(ResourceClaimError),
+    CannotBlock(InvalidTransition),
+}
+
+impl Display for PipeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::InvalidPipe => write!(f, "No such pipe."),
+            Self::EndClosed => write!(f, "That end of the pipe is closed."),
+            Self::NoSpaceAvailable(err) => write!(f, "Failed to create a pipe:\n{}", err),
+            Self::CannotBlock(err) => write!(f, "Failed to block on a pipe:\n{}", err),
+        }
+    }
+}
+
+impl Error for PipeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::NoSpaceAvailable(err) => Some(err),
+            Self::CannotBlock(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+
+    fn provide<'a>(&'a self, _request: &mut core::error::Request<'a>) {}
+}
+
+// A pipe is reclaimed once both ends are closed
+impl Resource for Option<Pipe> {
+    fn exhausted(&self) -> bool {
+        match self {
+            None => true,
+            Some(pipe) => !pipe.read_open && !pipe.write_open,
+        }
+    }
+}
+
+// Lock order is PIPES, then SCHEDULER_LOCK
+static PIPES: Mutex<ResourceManager<Option<Pipe>, MAX_PIPES>> = Mutex::new_ranked(
+    ResourceManager::new([const { None }; MAX_PIPES]),
+    LOCK_RANK_PIPES,
+);
+
+fn wake(reason: WaitReason, caller: Option<&ThreadHandle>) {
+    let _scheduler = SCHEDULER_LOCK.lock_blocking();
+    unsafe { PROCESS_TABLE.wake_threads(reason, caller) }
+}
+
+pub fn create() -> Result<ResourceId, PipeError> {
+    let mut pipes = PIPES.lock_blocking_mut();
+    let index = pipes
+        .claim_first(Some(Pipe {
+            buffer: Channel::new(),
+            read_open: true,
+            write_open: true,
+        }))
+        .map_err(PipeError::NoSpaceAvailable)?;
+    pipes.id_of(index).ok_or(PipeError::InvalidPipe)
+}
+
+// Reads whatever is buffered, up to dst.len() bytes. Reads nothing at end of file, which is once
+// the write end is closed and the buffer drained, and blocks the reader if it would otherwise wait.
+pub fn read(
+    id: ResourceId,
+    dst: &mut [u8],
+    reader: &ThreadHandle,
+) -> Result<PipeTransfer, PipeError> {
+    let mut pipes = PIPES.lock_blocking_mut();
+    let pipe = pipes
+        .get_mut(id)
+        .and_then(|pipe| pipe.as_mut())
+        .ok_or(PipeError::InvalidPipe)?;
+    if !pipe.read_open {
+        return Err(PipeError::EndClosed);
+    }
+    let count = dst
+        .iter_mut()
+        .map_while(|byte| pipe.buffer.try_recv().map(|val| *byte = val))
+        .count();
+    if count > 0 {
+        wake(WaitReason::PipeWritable(id), Some(reader));
+        return Ok(PipeTransfer::Done(count));
+    }
+    if dst.is_empty() || !pipe.write_open {
+        return Ok(PipeTransfer::Done(0));
+    }
+    // Still holding the pipe, so a writer can't slip in between the check and blocking
+    reader
+        .block(WaitReason::PipeReadable(id))
+        .map_err(PipeError::CannotBlock)?;
+    Ok(PipeTransfer::Blocked)
+}
+
+// Writes as much of src as fits, blocking the writer only if none of it does
+pub fn write(id: ResourceId, src: &[u8], writer: &ThreadHandle) -> Result<PipeTransfer, PipeError> {
+    let mut pipes = PIPES.lock_blocking_mut();
+    let pipe = pipes
+        .get_mut(id)
+        .and_then(|pipe| pipe.as_mut())
+        .ok_or(PipeError::InvalidPipe)?;
+    if !pipe.write_open || !pipe.read_open {
+        return Err(PipeError::EndClosed);
+    }
+    let count = src
+        .iter()
+        .take_while(|&&byte| pipe.buffer.try_send(byte).is_ok())
+        .count();
+    if count > 0 {
+        wake(WaitReason::PipeReadable(id), Some(writer));
+        return Ok(PipeTransfer::Done(count));
+    }
+    if src.is_empty() {
+        return Ok(PipeTransfer::Done(0));
+    }
+    writer
+        .block(WaitReason::PipeWritable(id))
+        .map_err(PipeError::CannotBlock)?;
+    Ok(PipeTransfer::Blocked)
+}
+
+// Anyone blocked on the pipe wakes up to find end of file, or a closed pipe
+pub fn close(id: ResourceId, end: PipeEnd) -> Result<(), PipeError> {
+    let mut pipes = PIPES.lock_blocking_mut();
+    let pipe = pipes
+        .get_mut(id)
+        .and_then(|pipe| pipe.as_mut())
+        .ok_or(PipeError::InvalidPipe)?;
+    let open = match end {
+        PipeEnd::Read => &mut pipe.read_open,
+        PipeEnd::Write => &mut pipe.write_open,
+    };
+    if !*open {
+        return Err(PipeError::EndClosed);
+    }
+    *open = false;
+    wake(WaitReason::PipeReadable(id), None);
+    wake(WaitReason::PipeWritable(id), None);
+    Ok(())
+}
@@ -1,15 +1,17 @@
 use core::error::Error;
 use core::fmt::Display;
 
-use crate::resource::{Resource, ResourceClaimError, ResourceManager};
+use crate::println;
+use crate::resource::{Resource, ResourceClaimError, ResourceId, ResourceManager};
 use crate::thread::ThreadHandle;
 
 use super::consts::MAX_THREADS;
-use super::thread::{CandidateThread, ThreadControlBlock};
+use super::thread::{CandidateThread, ThreadControlBlock, ThreadCreationError, WaitReason};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub enum ProcessStatus {
     Ready,
+    Exiting, // Becomes reclaimable once its last thread is dead
     Zombie,
 }
 
@@ -25,6 +27,7 @@ pub struct ProcessControlBlock {
 pub enum ProcessControlBlockCreationError {
     CouldNotClaimMainThread(ResourceClaimError),
     MainThreadHasNonZeroID,
+    CouldNotCreateMainThread(ThreadCreationError),
 }
 
 impl Display for ProcessControlBlockCreationError {
@@ -36,6 +39,13 @@ impl Display for ProcessControlBlockCreationError {
                 inner_err
             ),
             Self::MainThreadHasNonZeroID => write!(f, "Main thread was assigned non-zero ID."),
+            Self::CouldNotCreateMainThread(inner_err) => {
+                write!(
+                    f,
+                    "Failed to create main thread due to error:\n{}",
+                    inner_err
+                )
+            }
         }
     }
 }
@@ -45,6 +55,47 @@ impl Error for ProcessControlBlockCreationError {
         match self {
             Self::CouldNotClaimMainThread(err) => Some(err),
             Self::MainThreadHasNonZeroID => None,
+            Self::CouldNotCreateMainThread(err) => Some(err),
+        }
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+
+    fn provide<'a>(&'a self, _request: &mut core::error::Request<'a>) {}
+}
+
+#[derive(Debug)]
+pub enum ThreadSpawnError {
+    CouldNotClaimThread(ResourceClaimError),
+    InvalidThread(ThreadCreationError),
+}
+
+impl Display for ThreadSpawnError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::CouldNotClaimThread(inner_err) => write!(
+                f,
+                "Failed to claim thread from resource manager due to error:\n{}",
+                inner_err
+            ),
+            Self::InvalidThread(inner_err) => {
+                write!(f, "Failed to create thread due to error:\n{}", inner_err)
+            }
+        }
+    }
+}
+
+impl Error for ThreadSpawnError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::CouldNotClaimThread(err) => Some(err),
+            Self::InvalidThread(err) => Some(err),
         }
     }
 
@@ -74,12 +125,9 @@ impl ProcessControlBlock {
             memory_base: memory_base,
         };
 
-        match empty.threads.claim_first(Some(ThreadControlBlock::new(
-            main,
-            0,
-            priority,
-            memory_base,
-        ))) {
+        let main_thread = ThreadControlBlock::new(main, 0, id, priority, memory_base)
+            .map_err(ProcessControlBlockCreationError::CouldNotCreateMainThread)?;
+        match empty.threads.claim_first(Some(main_thread)) {
             Ok(index) => match index {
                 0 => Ok(empty),
                 _ => Err(ProcessControlBlockCreationError::MainThreadHasNonZeroID),
@@ -90,12 +138,103 @@ impl ProcessControlBlock {
         }
     }
 
-    pub fn choose<'a>(&'a mut self, mut candidate: CandidateThread<'a>) -> CandidateThread<'a> {
-        for maybe_thread in &mut self.threads.iter_mut() {
+    pub fn spawn_thread(
+        &mut self,
+        entry: u64,
+        stack_base: u64,
+        priority: u16,
+    ) -> Result<usize, ThreadSpawnError> {
+        // Checked before claiming a slot, so a bad stack doesn't use one up
+        ThreadControlBlock::align_stack_base(stack_base)
+            .map_err(ThreadSpawnError::InvalidThread)?;
+        let process_id = self.id;
+        self.threads
+            .emplace_first(|index| {
+                Some(
+                    ThreadControlBlock::new_at(
+                        entry,
+                        index as u16,
+                        process_id,
+                        priority,
+                        stack_base,
+                    )
+                    .expect("Stack base was already checked"),
+                )
+            })
+            .map_err(ThreadSpawnError::CouldNotClaimThread)
+    }
+
+    // Threads that are busy on another hart are killed the next time they trap or are considered
+    pub fn exit(&mut self) {
+        self.status = ProcessStatus::Exiting;
+        for thread in self.threads.iter_mut().flatten() {
+            if thread.get_handle().map(|handle| handle.kill()).is_err() {
+                thread.request_kill();
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    #[allow(dead_code)]
+    pub fn priority(&self) -> u16 {
+        self.priority
+    }
+
+    #[allow(dead_code)]
+    pub fn status(&self) -> ProcessStatus {
+        self.status
+    }
+
+    // Only threads that haven't exited
+    #[allow(dead_code)]
+    pub fn thread_count(&self) -> usize {
+        self.threads.iter().count()
+    }
+
+    #[allow(dead_code)]
+    pub fn get_thread_mut(&mut self, id: u16) -> Option<&mut ThreadControlBlock> {
+        self.threads
+            .find_mut(|thread| thread.as_ref().is_some_and(|thread| thread.id() == id))
+            .and_then(|(_, thread)| thread.as_mut())
+    }
+
+    pub fn thread_resource_id(&self, id: u16) -> Option<ResourceId> {
+        self.threads
+            .find(|thread| thread.as_ref().is_some_and(|thread| thread.id() == id))
+            .and_then(|(index, _)| self.threads.id_of(index))
+    }
+
+    pub fn thread_resource_id_at(&self, index: usize) -> Option<ResourceId> {
+        self.threads.id_of(index)
+    }
+
+    // Fails for a stale id, even if another thread has since taken the slot
+    pub fn get_thread_by_resource_id_mut(
+        &mut self,
+        id: ResourceId,
+    ) -> Option<&mut ThreadControlBlock> {
+        self.threads.get_mut(id).and_then(|thread| thread.as_mut())
+    }
+
+    pub fn choose<'a>(
+        &'a mut self,
+        process_index: usize,
+        mut candidate: CandidateThread<'a>,
+        last_scheduled_index: usize,
+        slots: usize,
+    ) -> CandidateThread<'a> {
+        for (thread_index, maybe_thread) in self.threads.iter_mut_indexed() {
             if let Some(thread) = maybe_thread {
                 if let Ok(handle) = thread.get_handle() {
-                    if let Some(new_best) = handle.consider(candidate.best) {
-                        candidate = CandidateThread::new(new_best, Some(handle));
+                    let index = process_index * MAX_THREADS + thread_index;
+                    if let Some(need) = handle.consider() {
+                        if candidate.is_beaten_by(need, index, last_scheduled_index, slots) {
+                            candidate = CandidateThread::new(need, index, Some(handle));
+                        }
                     }
                 }
             }
@@ -109,23 +248,93 @@ impl Resource for Option<ProcessControlBlock> {
         match self {
             None => true,
             Some(process) => match process.status {
+                ProcessStatus::Ready => false,
+                ProcessStatus::Exiting => process.threads.iter().next().is_none(),
                 ProcessStatus::Zombie => true,
-                _ => false,
             },
         }
     }
 }
 
 impl<const SIZE: usize> ResourceManager<Option<ProcessControlBlock>, SIZE> {
-    pub fn choose_next_thread(&mut self) -> Option<ThreadHandle> {
-        self.iter_mut()
-            .fold(
-                CandidateThread::default(),
-                |acc, candidate| match candidate {
-                    None => acc,
-                    Some(candidate_pcb) => candidate_pcb.choose(acc),
-                },
-            )
-            .handle
+    #[allow(dead_code)]
+    pub fn get_process(&self, id: u16) -> Option<&ProcessControlBlock> {
+        self.find(|process| process.as_ref().is_some_and(|process| process.id == id))
+            .and_then(|(_, process)| process.as_ref())
+    }
+
+    pub fn get_process_mut(&mut self, id: u16) -> Option<&mut ProcessControlBlock> {
+        self.find_mut(|process| process.as_ref().is_some_and(|process| process.id == id))
+            .and_then(|(_, process)| process.as_mut())
+    }
+
+    pub fn process_resource_id(&self, id: u16) -> Option<ResourceId> {
+        self.find(|process| process.as_ref().is_some_and(|process| process.id == id))
+            .and_then(|(index, _)| self.id_of(index))
+    }
+
+    pub fn get_process_by_resource_id_mut(
+        &mut self,
+        id: ResourceId,
+    ) -> Option<&mut ProcessControlBlock> {
+        self.get_mut(id).and_then(|process| process.as_mut())
+    }
+
+    // Threads that are busy on another hart are woken the next time they are considered. The caller
+    // is busy too, but it's the one making progress, so it's never woken.
+    pub fn wake_threads(&mut self, reason: WaitReason, caller: Option<&ThreadHandle>) {
+        for process in self.iter_mut().flatten() {
+            for thread in process.threads.iter_mut().flatten() {
+                if caller.is_some_and(|caller| caller.is_handle_of(thread))
+                    || !thread.is_waiting_on(reason)
+                {
+                    continue;
+                }
+                if thread
+                    .get_handle()
+                    .map(|handle| handle.wake(reason))
+                    .is_err()
+                {
+                    thread.request_wake();
+                }
+            }
+        }
+    }
+
+    pub fn has_live_threads(&self) -> bool {
+        self.iter()
+            .flatten()
+            .any(|process| process.threads.iter().next().is_some())
+    }
+
+    pub fn print_thread_states(&self) {
+        for process in self.iter().flatten() {
+            for thread in process.threads.iter().flatten() {
+                println!(
+                    "Process {} thread {}: {} ({} ticks)",
+                    process.id,
+                    thread.id(),
+                    thread.state(),
+                    thread.cpu_ticks()
+                );
+            }
+        }
+    }
+
+    pub fn choose_next_thread(&mut self, last_scheduled_index: &mut usize) -> Option<ThreadHandle> {
+        let slots = SIZE * MAX_THREADS;
+        let chosen = self.iter_mut_indexed().fold(
+            CandidateThread::default(),
+            |acc, (process_index, candidate)| match candidate {
+                None => acc,
+                Some(candidate_pcb) => {
+                    candidate_pcb.choose(process_index, acc, *last_scheduled_index, slots)
+                }
+            },
+        );
+        if chosen.handle.is_some() {
+            *last_scheduled_index = chosen.index;
+        }
+        chosen.handle
     }
 }
@@ -1,7 +1,17 @@
 use core::arch::global_asm;
 
+// Plain CSR reads and writes. Only meaningful in S-mode, and the values describe the current hart.
 extern "C" {
     pub fn get_stval() -> u64;
+    #[allow(dead_code)]
+    pub fn get_scause() -> u64;
+    #[allow(dead_code)]
+    pub fn get_satp() -> u64;
+    #[allow(dead_code)]
+    pub fn get_sstatus() -> u64;
+    // Overwrites every field at once, including SIE and FS, so read-modify-write with care
+    #[allow(dead_code)]
+    pub fn set_sstatus(value: u64);
 }
 
 global_asm!(include_str!("reg.S"));
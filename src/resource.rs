@@ -35,8 +35,31 @@ impl Error for ResourceClaimError {
     fn provide<'a>(&'a self, _request: &mut core::error::Request<'a>) {}
 }
 
+// Names one occupant of a slot. Slots are reused, so the generation tells a stale id apart from
+// whatever claimed the slot after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResourceId {
+    pub index: u16,
+    pub generation: u16,
+}
+
+impl ResourceId {
+    // How ids are handed to userspace
+    pub fn pack(self) -> u32 {
+        (self.generation as u32) << 16 | self.index as u32
+    }
+
+    pub fn unpack(packed: u32) -> ResourceId {
+        ResourceId {
+            index: packed as u16,
+            generation: (packed >> 16) as u16,
+        }
+    }
+}
+
 pub struct ResourceManager<R: Resource, const SIZE: usize> {
     data: [R; SIZE],
+    generations: [u16; SIZE], // Bumped every time a slot is claimed
 }
 
 impl<'a, R: Resource, const SIZE: usize> IntoIterator for &'a ResourceManager<R, SIZE> {
@@ -61,7 +84,46 @@ impl<'a, R: Resource, const SIZE: usize> IntoIterator for &'a mut ResourceManage
 
 impl<R: Resource, const SIZE: usize> ResourceManager<R, SIZE> {
     pub const fn new(data: [R; SIZE]) -> Self {
-        Self { data: data }
+        Self {
+            data: data,
+            generations: [0; SIZE],
+        }
+    }
+
+    fn bump_generation(&mut self, index: usize) {
+        self.generations[index] = self.generations[index].wrapping_add(1);
+    }
+
+    // The id of whatever currently occupies the slot
+    pub fn id_of(&self, index: usize) -> Option<ResourceId> {
+        match self.data.get(index) {
+            Some(resource) if !resource.exhausted() => Some(ResourceId {
+                index: u16::try_from(index).ok()?,
+                generation: self.generations[index],
+            }),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, id: ResourceId) -> Option<&R> {
+        let index = id.index as usize;
+        match self.data.get(index) {
+            Some(resource) if !resource.exhausted() && self.generations[index] == id.generation => {
+                Some(resource)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, id: ResourceId) -> Option<&mut R> {
+        let index = id.index as usize;
+        match self.data.get_mut(index) {
+            Some(resource) if !resource.exhausted() && self.generations[index] == id.generation => {
+                Some(resource)
+            }
+            _ => None,
+        }
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &R> {
@@ -72,6 +134,27 @@ impl<R: Resource, const SIZE: usize> ResourceManager<R, SIZE> {
         self.data.iter_mut().filter(|r| !r.exhausted())
     }
 
+    pub fn iter_mut_indexed(&mut self) -> impl Iterator<Item = (usize, &mut R)> {
+        self.data
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, r)| !r.exhausted())
+    }
+
+    pub fn find(&self, predicate: impl Fn(&R) -> bool) -> Option<(usize, &R)> {
+        self.data
+            .iter()
+            .enumerate()
+            .find(|(_, r)| !r.exhausted() && predicate(r))
+    }
+
+    pub fn find_mut(&mut self, predicate: impl Fn(&R) -> bool) -> Option<(usize, &mut R)> {
+        self.data
+            .iter_mut()
+            .enumerate()
+            .find(|(_, r)| !r.exhausted() && predicate(r))
+    }
+
     pub fn claim_first(&mut self, new_resource: R) -> Result<usize, ResourceClaimError> {
         if new_resource.exhausted() {
             return Err(ResourceClaimError::AddedExhaustedResource);
@@ -85,6 +168,7 @@ impl<R: Resource, const SIZE: usize> ResourceManager<R, SIZE> {
         {
             Some((index, resource)) => {
                 *resource = new_resource;
+                self.bump_generation(index);
                 Ok(index)
             }
             None => Err(ResourceClaimError::NoSpaceAvailable),
@@ -103,12 +187,60 @@ impl<R: Resource, const SIZE: usize> ResourceManager<R, SIZE> {
         {
             Some((index, resource)) => {
                 *resource = make_new_resource(index);
+                self.bump_generation(index);
                 Ok(index)
             }
             None => Err(ResourceClaimError::NoSpaceAvailable),
         }
     }
 
+    #[allow(dead_code)]
+    pub fn emplace_n<const N: usize>(
+        &mut self,
+        make_new_resource: impl Fn(usize) -> R,
+    ) -> Result<[usize; N], ResourceClaimError> {
+        let mut indices = [0; N];
+        let mut found = 0;
+        for (index, resource) in self.data.iter().enumerate() {
+            if found == N {
+                break;
+            }
+            if resource.exhausted() {
+                indices[found] = index;
+                found += 1;
+            }
+        }
+        if found < N {
+            return Err(ResourceClaimError::NoSpaceAvailable);
+        }
+        // Nothing is filled until there is room for all of them, so failing leaves no partial claim
+        for index in indices {
+            self.data[index] = make_new_resource(index);
+            self.bump_generation(index);
+        }
+        Ok(indices)
+    }
+
+    // Moves every live resource to the front, keeping their order, and returns how many there are.
+    // Moved resources get new ResourceIds, so ids handed out earlier stop working. Nothing may hold
+    // a reference or pointer into the manager either, which covers thread handles.
+    #[allow(dead_code)]
+    pub fn compact(&mut self) -> usize {
+        let mut live = 0;
+        for index in 0..SIZE {
+            if self.data[index].exhausted() {
+                continue;
+            }
+            if index != live {
+                self.data.swap(index, live);
+                self.bump_generation(index);
+                self.bump_generation(live);
+            }
+            live += 1;
+        }
+        live
+    }
+
     pub fn get_absolute(&self, index: usize) -> Option<&R> {
         self.data.get(index)
     }
@@ -0,0 +1,131 @@
+use core::{arch::global_asm, mem::size_of, ptr::addr_of};
+
+use crate::{
+    context::{activate_context, RegisterContext},
+    data::RingBuffer,
+    exception::USER_ENVIRONMENT_CALL,
+    heap::{check_page_double_free, check_page_round_trip, check_slab_round_trip},
+    interrupt::{IS_INTERRUPT_MASK, TIMER_INTERRUPT},
+    io::{Readable, Writable},
+    println,
+    sync::{Lock, Mutex},
+    time::{get_cycles, handle_timer_interrupt},
+};
+
+const REGISTER_PATTERN: u64 = 0x5e1f_7e57_0000_0000;
+const MAX_PROBE_ATTEMPTS: usize = 4;
+const NUM_REGISTERS: usize = size_of::<RegisterContext>() / size_of::<u64>();
+const LOCK_LATENCY_ROUNDS: u64 = 1000;
+
+extern "C" {
+    fn selftest_context_probe();
+}
+
+// RegisterContext is nothing but u64s, so it can be treated as an array of them
+fn registers(context: &RegisterContext) -> &[u64; NUM_REGISTERS] {
+    unsafe { &*(context as *const RegisterContext as *const [u64; NUM_REGISTERS]) }
+}
+
+fn registers_mut(context: &mut RegisterContext) -> &mut [u64; NUM_REGISTERS] {
+    unsafe { &mut *(context as *mut RegisterContext as *mut [u64; NUM_REGISTERS]) }
+}
+
+// Activates a probe that ecalls immediately, then checks every register came back unchanged
+fn check_context_round_trip(hart_id: u64) -> bool {
+    let mut context = RegisterContext::all_zero();
+    registers_mut(&mut context)
+        .iter_mut()
+        .enumerate()
+        .for_each(|(index, register)| *register = REGISTER_PATTERN | index as u64);
+    let expected = context;
+    for _ in 0..MAX_PROBE_ATTEMPTS {
+        let result = unsafe {
+            activate_context(
+                selftest_context_probe as usize as u64,
+                addr_of!(context) as u64,
+                hart_id,
+                0,
+            )
+        };
+        match result.cause {
+            USER_ENVIRONMENT_CALL => return registers(&context) == registers(&expected),
+            // Whatever was left in the comparator fired first, clear it and try again
+            cause if cause == IS_INTERRUPT_MASK | TIMER_INTERRUPT => handle_timer_interrupt(|_| ()),
+            _ => return false,
+        }
+    }
+    false
+}
+
+// Starts one byte in, so slices wrap around the end, and shifts the start every round
+fn check_ring_buffer() -> bool {
+    let ring: RingBuffer<4> = RingBuffer::new();
+    if !ring.is_empty() || ring.pop().is_some() || ring.push(0).is_err() || ring.pop() != Some(0) {
+        return false;
+    }
+    for round in 0..8u8 {
+        let filled = ring.push_slice(&[round, round + 1, round + 2]) == 3
+            && ring.push(round + 3).is_ok()
+            && ring.is_full()
+            && ring.len() == 4;
+        let rejected = ring.push(0xFF).is_err() && ring.push_slice(&[0xFF, 0xFF]) == 0;
+        let in_order = (0..4).all(|offset| ring.pop() == Some(round + offset));
+        let partial =
+            ring.push_slice(&[round; 6]) == 4 && (0..4).all(|_| ring.pop() == Some(round));
+        if !(filled && rejected && in_order && partial && ring.is_empty() && ring.pop().is_none()) {
+            return false;
+        }
+        // Shifts the start by one. Neither waits, since there's room to write and a byte to read.
+        ring.write_blocking(round);
+        if ring.read_blocking() != round {
+            return false;
+        }
+    }
+    true
+}
+
+// Average cycles to claim and release an uncontended lock, first with a bare compare exchange,
+// which is all claim_blocking did before it backed off, then through lock_blocking
+fn measure_lock_latency() -> (u64, u64) {
+    let lock = Lock::new();
+    let start = unsafe { get_cycles() };
+    for _ in 0..LOCK_LATENCY_ROUNDS {
+        let _ = lock.claim();
+        let _ = lock.release();
+    }
+    let bare = unsafe { get_cycles() } - start;
+    let mutex = Mutex::new(());
+    let start = unsafe { get_cycles() };
+    for _ in 0..LOCK_LATENCY_ROUNDS {
+        drop(mutex.lock_blocking());
+    }
+    let blocking = unsafe { get_cycles() } - start;
+    (bare / LOCK_LATENCY_ROUNDS, blocking / LOCK_LATENCY_ROUNDS)
+}
+
+fn report(name: &str, passed: bool) {
+    println!(
+        "Self test {}: {}",
+        name,
+        match passed {
+            true => "pass",
+            false => "FAIL",
+        }
+    );
+}
+
+// Must run on the boot hart before any other hart is started
+pub fn run(hart_id: u64) {
+    report("context round trip", check_context_round_trip(hart_id));
+    report("page allocator", check_page_round_trip());
+    report("page double free", check_page_double_free());
+    report("slab allocator", check_slab_round_trip());
+    report("ring buffer", check_ring_buffer());
+    let (bare, blocking) = measure_lock_latency();
+    println!(
+        "Uncontended lock: {} cycles bare, {} cycles with lock_blocking",
+        bare, blocking
+    );
+}
+
+global_asm!(include_str!("selftest.S"));
@@ -0,0 +1,57 @@
+use core::{
+    arch::global_asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{consts::MAX_HARTS, sync::retry_with_backoff};
+
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const SECONDARY_BOOT_ATTEMPTS: usize = 100_000;
+
+// Counts the boot hart, plus every secondary that has reached the scheduler
+static HARTS_STARTED: AtomicUsize = AtomicUsize::new(1);
+
+pub fn current_hart_id() -> usize {
+    unsafe { get_hart_id() as usize }
+}
+
+pub fn mark_hart_started() {
+    HARTS_STARTED.fetch_add(1, Ordering::AcqRel);
+}
+
+pub fn harts_started() -> usize {
+    HARTS_STARTED.load(Ordering::Acquire)
+}
+
+// Starts every other hart, and waits for them to check in. Returns the number of running harts.
+pub fn start_secondary_harts(boot_hart_id: u64) -> usize {
+    let mut expected = 1;
+    let mut parked_mask = 0;
+    for hart_id in (0..MAX_HARTS as u64).filter(|&hart_id| hart_id != boot_hart_id) {
+        match unsafe { sbi_hart_start(hart_id, _start_secondary as usize as u64, 0) } {
+            SBI_SUCCESS => expected += 1,
+            // Already running, which means it entered _start with us and is parked waiting for an IPI
+            SBI_ERR_ALREADY_AVAILABLE => {
+                expected += 1;
+                parked_mask |= 1 << hart_id;
+            }
+            _ => (), // Not a hart we can run on
+        }
+    }
+    if parked_mask != 0 {
+        unsafe { sbi_send_ipi(parked_mask, 0) };
+    }
+    retry_with_backoff(SECONDARY_BOOT_ATTEMPTS, || harts_started() >= expected);
+    harts_started()
+}
+
+extern "C" {
+    fn get_hart_id() -> u64;
+    fn sbi_hart_start(hart_id: u64, start_address: u64, opaque: u64) -> i64;
+    pub fn sbi_send_ipi(hart_mask: u64, hart_mask_base: u64) -> i64;
+    fn _start_secondary();
+}
+
+global_asm!(include_str!("smp.S"));
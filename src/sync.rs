@@ -1,26 +1,66 @@
 use core::{
     cell::UnsafeCell,
     error::Error,
-    fmt::Display,
+    fmt::{Debug, Display},
+    hint::spin_loop,
+    marker::PhantomData,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
 };
 
+#[cfg(feature = "debug_locks")]
+use crate::smp::current_hart_id;
+use crate::{consts::MAX_LOCK_ACQUIRE_SPINS, syscall::p_yield};
+
+#[cfg(feature = "debug_locks")]
+const NO_HOLDER: usize = usize::MAX;
+
+const BACKOFF_FREE_ATTEMPTS: usize = 16;
+const MAX_BACKOFF_SHIFT: usize = 10;
+const MAX_LOCK_ACQUIRE_ATTEMPTS: usize = backoff_attempts_for_spins(MAX_LOCK_ACQUIRE_SPINS);
+// Attempts between yields in claim_cooperative
+const COOPERATIVE_CLAIM_ATTEMPTS: usize = 1024;
+
 pub struct Lock {
     claimed: AtomicBool,
+    #[cfg(feature = "debug_locks")]
+    holder: AtomicUsize,
+}
+
+pub struct Semaphore {
+    count: AtomicUsize,
+}
+
+const ONCE_UNINIT: u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_DONE: u8 = 2;
+
+pub struct Once {
+    state: AtomicU8,
 }
 
+// Ranks run from 1 to 31, 0 opts out of ordering checks
+pub type LockRank = u32;
+pub const UNRANKED: LockRank = 0;
+
 pub struct Mutex<T> {
     guarded: UnsafeCell<T>,
     lock: Lock,
+    #[cfg(feature = "lock_ranks")]
+    rank: LockRank,
 }
 
+// Guards must be released on the hart that took them, since the lock may record its holder, so
+// they're kept !Send. A raw pointer marker also makes them !Sync, which is given back below only
+// when sharing the guard can't hand out a &T that T doesn't allow.
 pub struct MutexGuardMut<'a, T: 'a> {
     mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
 }
 
 pub struct MutexGuard<'a, T: 'a> {
     mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
 }
 
 #[derive(Debug)]
@@ -56,23 +96,54 @@ impl Lock {
     pub const fn new() -> Lock {
         Lock {
             claimed: AtomicBool::new(false),
+            #[cfg(feature = "debug_locks")]
+            holder: AtomicUsize::new(NO_HOLDER),
         }
     }
 
     pub fn claim(&self) -> Result<bool, bool> {
-        self.claimed
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+        let claimed =
+            self.claimed
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed);
+        #[cfg(feature = "debug_locks")]
+        if claimed.is_ok() {
+            self.holder.store(current_hart_id(), Ordering::Relaxed);
+        }
+        claimed
+    }
+
+    // Attempts rather than cycles, since later attempts wait out longer and longer backoffs
+    pub fn claim_for_attempts(&self, max_attempts: usize) -> bool {
+        retry_with_backoff(max_attempts, || self.claim().is_ok())
     }
 
     pub fn claim_blocking(&self) {
-        let mut claimed = self.claim();
-        while claimed.is_err() {
-            claimed = self.claim();
+        if !self.claim_for_attempts(MAX_LOCK_ACQUIRE_ATTEMPTS) {
+            #[cfg(feature = "debug_locks")]
+            match self.holder.load(Ordering::Relaxed) {
+                NO_HOLDER => panic!("Took too long to claim lock! (no recorded holder)"),
+                hart_id => panic!("Took too long to claim lock! (held by hart {})", hart_id),
+            }
+            #[cfg(not(feature = "debug_locks"))]
+            panic!("Took too long to claim lock!");
         }
         assert!(self.is_held());
     }
 
+    // Yields between bursts of attempts instead of spinning until it panics, so whoever holds the
+    // lock gets a chance to run. Only for threads, since it yields with a syscall, and never while
+    // holding another lock, since everything waiting on that one would wait out the yield too.
+    #[allow(dead_code)]
+    pub fn claim_cooperative(&self) {
+        while !self.claim_for_attempts(COOPERATIVE_CLAIM_ATTEMPTS) {
+            p_yield();
+        }
+    }
+
     pub fn release(&self) -> Result<bool, bool> {
+        // Cleared first, since the lock may be claimed again as soon as it's released
+        #[cfg(feature = "debug_locks")]
+        self.holder.store(NO_HOLDER, Ordering::Relaxed);
         self.claimed
             .compare_exchange(true, false, Ordering::SeqCst, Ordering::Relaxed)
     }
@@ -82,44 +153,270 @@ impl Lock {
     }
 }
 
+#[allow(dead_code)]
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: AtomicUsize::new(count),
+        }
+    }
+
+    pub fn try_acquire(&self) -> Result<(), ()> {
+        match self
+            .count
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            }) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(()),
+        }
+    }
+
+    pub fn acquire_blocking(&self) {
+        if !retry_with_backoff(MAX_LOCK_ACQUIRE_ATTEMPTS, || self.try_acquire().is_ok()) {
+            panic!("Took too long to acquire semaphore!");
+        }
+    }
+
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl Once {
+    pub const fn new() -> Once {
+        Once {
+            state: AtomicU8::new(ONCE_UNINIT),
+        }
+    }
+
+    // Runs f on the first call only. Every other caller waits for that call to finish, so whatever
+    // f sets up is ready once call_once returns, on any hart.
+    pub fn call_once(&self, f: impl FnOnce()) {
+        match self.state.compare_exchange(
+            ONCE_UNINIT,
+            ONCE_RUNNING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                f();
+                self.state.store(ONCE_DONE, Ordering::Release);
+            }
+            Err(_) => {
+                if !retry_with_backoff(MAX_LOCK_ACQUIRE_ATTEMPTS, || self.is_completed()) {
+                    panic!("Took too long waiting for initialization!");
+                }
+            }
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == ONCE_DONE
+    }
+}
+
+// Retries `attempt` until it succeeds or `max_attempts` is reached, returning whether it succeeded.
+// After a few immediate retries, waits an exponentially growing number of spin-loop hints between
+// attempts. This doesn't `wfi`, since the kernel runs with interrupts masked and a lock released by
+// another hart raises no interrupt to wake us.
+pub fn retry_with_backoff(max_attempts: usize, mut attempt: impl FnMut() -> bool) -> bool {
+    for attempts in 0..max_attempts {
+        if attempt() {
+            return true;
+        }
+        if attempts >= BACKOFF_FREE_ATTEMPTS {
+            let shift = (attempts - BACKOFF_FREE_ATTEMPTS).min(MAX_BACKOFF_SHIFT);
+            (0..1 << shift).for_each(|_| spin_loop());
+        }
+    }
+    false
+}
+
+// How many attempts retry_with_backoff takes to spin about `spins` times between them
+const fn backoff_attempts_for_spins(spins: usize) -> usize {
+    let mut attempts = BACKOFF_FREE_ATTEMPTS;
+    let mut spun = 0;
+    while spun < spins {
+        // Same as retry_with_backoff, but min isn't const
+        let shift = attempts - BACKOFF_FREE_ATTEMPTS;
+        let shift = if shift < MAX_BACKOFF_SHIFT {
+            shift
+        } else {
+            MAX_BACKOFF_SHIFT
+        };
+        spun += 1 << shift;
+        attempts += 1;
+    }
+    attempts
+}
+
 impl<T> Mutex<T> {
     pub const fn new(val: T) -> Mutex<T> {
+        Mutex::new_ranked(val, UNRANKED)
+    }
+
+    // Ranked mutexes that nest must be taken in increasing rank, which is checked with the
+    // lock_ranks feature. Ranks are in consts.rs.
+    pub const fn new_ranked(val: T, rank: LockRank) -> Mutex<T> {
+        #[cfg(not(feature = "lock_ranks"))]
+        let _ = rank;
         Mutex {
             guarded: UnsafeCell::new(val),
             lock: Lock::new(),
+            #[cfg(feature = "lock_ranks")]
+            rank,
         }
     }
 
     pub fn lock_mut(&self) -> Result<MutexGuardMut<'_, T>, MutexLockError> {
+        self.check_rank();
         match self.lock.claim() {
-            Ok(_) => Ok(MutexGuardMut { mutex: self }),
+            Ok(_) => Ok(self.guard_mut()),
             Err(_) => Err(MutexLockError::AlreadyHeld),
         }
     }
 
     pub fn lock(&self) -> Result<MutexGuard<'_, T>, MutexLockError> {
+        self.check_rank();
         match self.lock.claim() {
-            Ok(_) => Ok(MutexGuard { mutex: self }),
+            Ok(_) => Ok(self.guard()),
             Err(_) => Err(MutexLockError::AlreadyHeld),
         }
     }
 
+    #[allow(dead_code)]
+    pub fn try_lock_mut_for_attempts(
+        &self,
+        max_attempts: usize,
+    ) -> Result<MutexGuardMut<'_, T>, MutexLockError> {
+        self.check_rank();
+        match self.lock.claim_for_attempts(max_attempts) {
+            true => Ok(self.guard_mut()),
+            false => Err(MutexLockError::AlreadyHeld),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn try_lock_for_attempts(
+        &self,
+        max_attempts: usize,
+    ) -> Result<MutexGuard<'_, T>, MutexLockError> {
+        self.check_rank();
+        match self.lock.claim_for_attempts(max_attempts) {
+            true => Ok(self.guard()),
+            false => Err(MutexLockError::AlreadyHeld),
+        }
+    }
+
     pub fn lock_blocking_mut(&self) -> MutexGuardMut<'_, T> {
+        self.check_rank();
         self.lock.claim_blocking();
-        MutexGuardMut { mutex: self }
+        self.guard_mut()
     }
 
     pub fn lock_blocking(&self) -> MutexGuard<'_, T> {
+        self.check_rank();
         self.lock.claim_blocking();
-        MutexGuard { mutex: self }
+        self.guard()
+    }
+
+    // Runs `f` with the lock held, releasing it before returning
+    pub fn with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock_blocking_mut())
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.lock_blocking())
     }
 
     pub fn is_held(&self) -> bool {
         self.lock.is_held()
     }
+
+    // Only once the lock is claimed
+    fn guard_mut(&self) -> MutexGuardMut<'_, T> {
+        #[cfg(feature = "lock_ranks")]
+        lock_ranks::note_claimed(self.rank);
+        MutexGuardMut {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    fn guard(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "lock_ranks")]
+        lock_ranks::note_claimed(self.rank);
+        MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    // Checked before trying, since a blocking claim taken out of order may never return
+    #[inline(always)]
+    fn check_rank(&self) {
+        #[cfg(feature = "lock_ranks")]
+        lock_ranks::check(self.rank);
+    }
+
+    fn release(&self) {
+        #[cfg(feature = "lock_ranks")]
+        lock_ranks::note_released(self.rank);
+        match self.lock.release() {
+            Ok(_) => (),
+            Err(_) => panic!("Mutex lock failed to release."),
+        }
+    }
+}
+
+// Each hart's held ranks as a bit mask. Guards can be dropped in any order, so a mask works where a
+// stack wouldn't, and same rank locks are never nested so one bit per rank is enough.
+#[cfg(feature = "lock_ranks")]
+mod lock_ranks {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use super::{LockRank, UNRANKED};
+    use crate::{consts::MAX_HARTS, smp::current_hart_id};
+
+    static HELD_RANKS: [AtomicU32; MAX_HARTS] = [const { AtomicU32::new(0) }; MAX_HARTS];
+
+    pub fn check(rank: LockRank) {
+        if rank == UNRANKED {
+            return;
+        }
+        let held = HELD_RANKS[current_hart_id()].load(Ordering::Relaxed);
+        if held >> rank != 0 {
+            panic!(
+                "Lock of rank {} taken while holding a lock of rank {}!",
+                rank,
+                u32::BITS - 1 - held.leading_zeros()
+            );
+        }
+    }
+
+    pub fn note_claimed(rank: LockRank) {
+        if rank != UNRANKED {
+            HELD_RANKS[current_hart_id()].fetch_or(1 << rank, Ordering::Relaxed);
+        }
+    }
+
+    pub fn note_released(rank: LockRank) {
+        if rank != UNRANKED {
+            HELD_RANKS[current_hart_id()].fetch_and(!(1 << rank), Ordering::Relaxed);
+        }
+    }
 }
 
-unsafe impl<T> Sync for Mutex<T> {}
+// Locking hands out a &mut T to whichever hart gets there, so the value must be fine to move
+// between harts. Mutex is Send on its own whenever T is.
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Sync> Sync for MutexGuardMut<'_, T> {}
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
 
 impl<'a, T> Deref for MutexGuardMut<'a, T> {
     type Target = T;
@@ -149,10 +446,7 @@ impl<'a, T> DerefMut for MutexGuardMut<'a, T> {
 
 impl<'a, T> Drop for MutexGuardMut<'a, T> {
     fn drop(&mut self) {
-        match self.mutex.lock.release() {
-            Ok(_) => (),
-            Err(_) => panic!("Mutex lock failed to release."),
-        }
+        self.mutex.release();
     }
 }
 
@@ -172,9 +466,31 @@ impl<'a, T> Deref for MutexGuard<'a, T> {
 
 impl<'a, T> Drop for MutexGuard<'a, T> {
     fn drop(&mut self) {
-        match self.mutex.lock.release() {
-            Ok(_) => (),
-            Err(_) => panic!("Mutex lock failed to release."),
-        }
+        self.mutex.release();
+    }
+}
+
+// Guards print as whatever they guard
+impl<'a, T: Debug> Debug for MutexGuardMut<'a, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: Display> Display for MutexGuardMut<'a, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: Debug> Debug for MutexGuard<'a, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: Display> Display for MutexGuard<'a, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(&**self, f)
     }
 }
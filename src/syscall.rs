@@ -1,9 +1,37 @@
-use core::arch::global_asm;
+use core::{arch::global_asm, error::Error, fmt::Display};
 
-use crate::thread::{ThreadActivationResult, ThreadHandle};
+use crate::{
+    heap::is_ram_range,
+    pipe::{self, PipeEnd, PipeTransfer},
+    println,
+    resource::ResourceId,
+    thread::{ResumeMode, ThreadActivationResult, ThreadHandle},
+    uart::{uart0_base, UartHandler},
+    PROCESS_TABLE, SCHEDULER_LOCK,
+};
 
 pub const EXIT: u64 = 0;
 pub const YIELD: u64 = 1;
+pub const SPAWN: u64 = 2;
+pub const GETPID: u64 = 3;
+pub const GETTID: u64 = 4;
+pub const WRITE: u64 = 5;
+pub const KILL: u64 = 6;
+pub const YIELD_TO: u64 = 7;
+pub const EXIT_GROUP: u64 = 8;
+pub const PIPE_CREATE: u64 = 9;
+pub const PIPE_WRITE: u64 = 10;
+pub const PIPE_READ: u64 = 11;
+pub const PIPE_CLOSE: u64 = 12;
+pub const SET_QUANTUM: u64 = 13;
+pub const NOTIFY: u64 = 14;
+pub const CHECK_NOTIFY: u64 = 15;
+
+pub const PIPE_READ_END: u64 = 0;
+pub const PIPE_WRITE_END: u64 = 1;
+
+pub const SYSCALL_ERROR: u64 = u64::MAX;
+pub const SYSCALL_BUSY: u64 = u64::MAX - 1;
 
 #[no_mangle]
 pub extern "C" fn exit(status: u64) -> ! {
@@ -20,18 +48,450 @@ pub extern "C" fn p_yield() {
     }
 }
 
+#[allow(dead_code)]
+pub extern "C" fn spawn(entry: extern "C" fn() -> u64, stack_base: u64, priority: u16) -> i64 {
+    unsafe { syscall_3a(SPAWN, entry as usize as u64, stack_base, priority as u64) }
+}
+
+#[allow(dead_code)]
+pub extern "C" fn getpid() -> u32 {
+    unsafe { syscall(GETPID) as u32 }
+}
+
+#[allow(dead_code)]
+pub extern "C" fn gettid() -> u32 {
+    unsafe { syscall(GETTID) as u32 }
+}
+
+#[allow(dead_code)]
+pub fn write(buf: &[u8]) -> i64 {
+    unsafe { syscall_2a(WRITE, buf.as_ptr() as u64, buf.len() as u64) }
+}
+
+#[allow(dead_code)]
+pub extern "C" fn kill(thread_id: u32, process_id: u32) -> i64 {
+    unsafe { syscall_2a(KILL, thread_id as u64, process_id as u64) }
+}
+
+#[allow(dead_code)]
+pub extern "C" fn exit_group(status: u64) -> ! {
+    unsafe {
+        syscall_1a(EXIT_GROUP, status);
+    }
+    unreachable!("Execution survived exiting.")
+}
+
+#[allow(dead_code)]
+pub extern "C" fn yield_to(thread_id: u32) -> i64 {
+    unsafe { syscall_1a(YIELD_TO, thread_id as u64) }
+}
+
+// Returns the previous quantum
+#[allow(dead_code)]
+pub extern "C" fn set_quantum(quantum_ms: u64) -> i64 {
+    unsafe { syscall_1a(SET_QUANTUM, quantum_ms) }
+}
+
+// Sets bits in the target's pending notifications, which stay set until it checks them
+#[allow(dead_code)]
+pub extern "C" fn notify(thread_id: u32, process_id: u32, notifications: u32) -> i64 {
+    unsafe {
+        syscall_3a(
+            NOTIFY,
+            thread_id as u64,
+            process_id as u64,
+            notifications as u64,
+        )
+    }
+}
+
+// Returns and clears the caller's pending notifications
+#[allow(dead_code)]
+pub extern "C" fn check_notify() -> u32 {
+    unsafe { syscall(CHECK_NOTIFY) as u32 }
+}
+
+#[allow(dead_code)]
+pub extern "C" fn pipe_create() -> i64 {
+    unsafe { syscall(PIPE_CREATE) }
+}
+
+// Blocks until at least one byte is written, and returns how many were
+#[allow(dead_code)]
+pub fn pipe_write(pipe_id: u32, buf: &[u8]) -> i64 {
+    unsafe {
+        syscall_3a(
+            PIPE_WRITE,
+            pipe_id as u64,
+            buf.as_ptr() as u64,
+            buf.len() as u64,
+        )
+    }
+}
+
+// Blocks until something can be read, and returns how many bytes were. Zero means end of file.
+#[allow(dead_code)]
+pub fn pipe_read(pipe_id: u32, buf: &mut [u8]) -> i64 {
+    unsafe {
+        syscall_3a(
+            PIPE_READ,
+            pipe_id as u64,
+            buf.as_mut_ptr() as u64,
+            buf.len() as u64,
+        )
+    }
+}
+
+#[allow(dead_code)]
+pub extern "C" fn pipe_close(pipe_id: u32, end: u64) -> i64 {
+    unsafe { syscall_2a(PIPE_CLOSE, pipe_id as u64, end) }
+}
+
+pub enum Syscall {
+    Exit(u64),
+    Yield,
+    Spawn {
+        entry: u64,
+        stack_base: u64,
+        priority: u16,
+    },
+    GetPid,
+    GetTid,
+    Write {
+        buf: u64,
+        len: u64,
+    },
+    // Ids from userspace are packed ResourceIds, so a stale one can't name a slot's new occupant
+    Kill {
+        thread_id: ResourceId,
+        process_id: ResourceId,
+    },
+    YieldTo {
+        thread_id: ResourceId,
+    },
+    ExitGroup(u64),
+    SetQuantum(u64),
+    Notify {
+        thread_id: ResourceId,
+        process_id: ResourceId,
+        notifications: u32,
+    },
+    CheckNotify,
+    PipeCreate,
+    PipeWrite {
+        pipe_id: ResourceId,
+        buf: u64,
+        len: u64,
+    },
+    PipeRead {
+        pipe_id: ResourceId,
+        buf: u64,
+        len: u64,
+    },
+    PipeClose {
+        pipe_id: ResourceId,
+        end: PipeEnd,
+    },
+}
+
+#[derive(Debug)]
+pub enum SyscallDecodeError {
+    UnknownSyscall(u64),
+    InvalidArgument { code: u64, index: usize },
+}
+
+impl Display for SyscallDecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::UnknownSyscall(code) => write!(f, "Unknown syscall: {:#010x}", code),
+            Self::InvalidArgument { code, index } => write!(
+                f,
+                "Argument {} is out of range for syscall {:#010x}",
+                index, code
+            ),
+        }
+    }
+}
+
+impl Error for SyscallDecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+
+    fn provide<'a>(&'a self, _request: &mut core::error::Request<'a>) {}
+}
+
+impl TryFrom<[u64; 8]> for Syscall {
+    type Error = SyscallDecodeError;
+
+    fn try_from(args: [u64; 8]) -> Result<Self, Self::Error> {
+        let [code, arg1, arg2, arg3, ..] = args;
+        let narrow = |index: usize, val: u64| {
+            u16::try_from(val).map_err(|_| SyscallDecodeError::InvalidArgument { code, index })
+        };
+        let resource_id = |index: usize, val: u64| {
+            u32::try_from(val)
+                .map(ResourceId::unpack)
+                .map_err(|_| SyscallDecodeError::InvalidArgument { code, index })
+        };
+        match code {
+            EXIT => Ok(Syscall::Exit(arg1)),
+            YIELD => Ok(Syscall::Yield),
+            SPAWN => Ok(Syscall::Spawn {
+                entry: arg1,
+                stack_base: arg2,
+                priority: narrow(3, arg3)?,
+            }),
+            GETPID => Ok(Syscall::GetPid),
+            GETTID => Ok(Syscall::GetTid),
+            WRITE => Ok(Syscall::Write {
+                buf: arg1,
+                len: arg2,
+            }),
+            KILL => Ok(Syscall::Kill {
+                thread_id: resource_id(1, arg1)?,
+                process_id: resource_id(2, arg2)?,
+            }),
+            YIELD_TO => Ok(Syscall::YieldTo {
+                thread_id: resource_id(1, arg1)?,
+            }),
+            EXIT_GROUP => Ok(Syscall::ExitGroup(arg1)),
+            SET_QUANTUM => Ok(Syscall::SetQuantum(arg1)),
+            NOTIFY => Ok(Syscall::Notify {
+                thread_id: resource_id(1, arg1)?,
+                process_id: resource_id(2, arg2)?,
+                notifications: u32::try_from(arg3)
+                    .map_err(|_| SyscallDecodeError::InvalidArgument { code, index: 3 })?,
+            }),
+            CHECK_NOTIFY => Ok(Syscall::CheckNotify),
+            PIPE_CREATE => Ok(Syscall::PipeCreate),
+            PIPE_WRITE => Ok(Syscall::PipeWrite {
+                pipe_id: resource_id(1, arg1)?,
+                buf: arg2,
+                len: arg3,
+            }),
+            PIPE_READ => Ok(Syscall::PipeRead {
+                pipe_id: resource_id(1, arg1)?,
+                buf: arg2,
+                len: arg3,
+            }),
+            PIPE_CLOSE => Ok(Syscall::PipeClose {
+                pipe_id: resource_id(1, arg1)?,
+                end: match arg2 {
+                    PIPE_READ_END => PipeEnd::Read,
+                    PIPE_WRITE_END => PipeEnd::Write,
+                    _ => return Err(SyscallDecodeError::InvalidArgument { code, index: 2 }),
+                },
+            }),
+            unknown => Err(SyscallDecodeError::UnknownSyscall(unknown)),
+        }
+    }
+}
+
 pub fn handle_syscall(
     activation: &ThreadActivationResult,
     handle: &ThreadHandle,
     _supervisor: bool,
 ) {
-    let args = activation.thread.get_args();
-    let code = args.get(0).unwrap();
-    match *code {
-        EXIT => handle.kill(),
-        YIELD => handle.resolve_interrupt_or_kill(true),
-        _ => unimplemented!("Unknown Syscall: {:#010x}", *code), // Handle unknown syscalls later
+    let thread = &activation.thread;
+    // Malformed syscalls only fail the caller, they must never bring down the kernel
+    let return_val = match Syscall::try_from(thread.get_args()) {
+        Ok(Syscall::Exit(status)) => {
+            println!("Thread {} exited with status {}", thread.id(), status);
+            handle.kill();
+            return;
+        }
+        Ok(Syscall::ExitGroup(status)) => {
+            let process_id = thread.owning_process_id();
+            println!("Process {} exited with status {}", process_id, status);
+            exit_process(process_id);
+            handle.kill();
+            return;
+        }
+        Ok(Syscall::Yield) => {
+            handle.note_yield();
+            0
+        }
+        Ok(Syscall::Spawn {
+            entry,
+            stack_base,
+            priority,
+        }) => spawn_thread(thread.owning_process_id(), entry, stack_base, priority),
+        Ok(Syscall::GetPid) => process_resource_id(thread.owning_process_id()),
+        Ok(Syscall::GetTid) => thread_resource_id(thread.id(), thread.owning_process_id()),
+        Ok(Syscall::Write { buf, len }) => write_console(buf, len),
+        Ok(Syscall::Kill {
+            thread_id,
+            process_id,
+        }) => kill_thread(thread_id, process_id),
+        Ok(Syscall::YieldTo { thread_id }) => {
+            handle.note_yield();
+            yield_to_thread(thread_id, thread.owning_process_id())
+        }
+        Ok(Syscall::SetQuantum(quantum_ms)) => {
+            handle.set_quantum_ms(quantum_ms).unwrap_or(SYSCALL_ERROR)
+        }
+        Ok(Syscall::Notify {
+            thread_id,
+            process_id,
+            notifications,
+        }) => notify_thread(thread_id, process_id, notifications),
+        Ok(Syscall::CheckNotify) => thread.take_notifications() as u64,
+        Ok(Syscall::PipeCreate) => match pipe::create() {
+            Ok(pipe_id) => pipe_id.pack() as u64,
+            Err(_) => SYSCALL_ERROR,
+        },
+        Ok(Syscall::PipeWrite { pipe_id, buf, len }) => match write_pipe(pipe_id, buf, len, handle)
+        {
+            Some(val) => val,
+            None => return, // Blocked, and retries the ecall once woken
+        },
+        Ok(Syscall::PipeRead { pipe_id, buf, len }) => match read_pipe(pipe_id, buf, len, handle) {
+            Some(val) => val,
+            None => return,
+        },
+        Ok(Syscall::PipeClose { pipe_id, end }) => match pipe::close(pipe_id, end) {
+            Ok(()) => 0,
+            Err(_) => SYSCALL_ERROR,
+        },
+        Err(_) => SYSCALL_ERROR,
+    };
+    handle.set_return_val(return_val);
+    handle.resolve_interrupt_or_kill(ResumeMode::Skip);
+}
+
+fn spawn_thread(process_id: u16, entry: u64, stack_base: u64, priority: u16) -> u64 {
+    if entry == 0 {
+        return SYSCALL_ERROR;
+    }
+    let _scheduler = SCHEDULER_LOCK.lock_blocking();
+    match unsafe { PROCESS_TABLE.get_process_mut(process_id) } {
+        Some(process) => match process.spawn_thread(entry, stack_base, priority) {
+            Ok(index) => process
+                .thread_resource_id_at(index)
+                .map_or(SYSCALL_ERROR, |id| id.pack() as u64),
+            Err(_) => SYSCALL_ERROR,
+        },
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn process_resource_id(process_id: u16) -> u64 {
+    let _scheduler = SCHEDULER_LOCK.lock_blocking();
+    match unsafe { PROCESS_TABLE.process_resource_id(process_id) } {
+        Some(id) => id.pack() as u64,
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn thread_resource_id(thread_id: u16, process_id: u16) -> u64 {
+    let _scheduler = SCHEDULER_LOCK.lock_blocking();
+    match unsafe { PROCESS_TABLE.get_process_mut(process_id) }
+        .and_then(|process| process.thread_resource_id(thread_id))
+    {
+        Some(id) => id.pack() as u64,
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn exit_process(process_id: u16) {
+    let _scheduler = SCHEDULER_LOCK.lock_blocking();
+    if let Some(process) = unsafe { PROCESS_TABLE.get_process_mut(process_id) } {
+        process.exit();
+    }
+}
+
+fn kill_thread(thread_id: ResourceId, process_id: ResourceId) -> u64 {
+    let _scheduler = SCHEDULER_LOCK.lock_blocking();
+    let Some(process) = (unsafe { PROCESS_TABLE.get_process_by_resource_id_mut(process_id) })
+    else {
+        return SYSCALL_ERROR;
+    };
+    match process.get_thread_by_resource_id_mut(thread_id) {
+        // The handle is held whenever the thread is being scheduled or run, including by the caller
+        Some(thread) => match thread.get_handle() {
+            Ok(target) => {
+                target.kill();
+                0
+            }
+            Err(_) => SYSCALL_BUSY,
+        },
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn notify_thread(thread_id: ResourceId, process_id: ResourceId, notifications: u32) -> u64 {
+    let _scheduler = SCHEDULER_LOCK.lock_blocking();
+    let Some(process) = (unsafe { PROCESS_TABLE.get_process_by_resource_id_mut(process_id) })
+    else {
+        return SYSCALL_ERROR;
+    };
+    match process.get_thread_by_resource_id_mut(thread_id) {
+        Some(thread) => {
+            thread.notify(notifications);
+            0
+        }
+        None => SYSCALL_ERROR,
+    }
+}
+
+// The caller always yields, this only decides whether the target is guaranteed to run next
+fn yield_to_thread(thread_id: ResourceId, process_id: u16) -> u64 {
+    let _scheduler = SCHEDULER_LOCK.lock_blocking();
+    let Some(process) = (unsafe { PROCESS_TABLE.get_process_mut(process_id) }) else {
+        return SYSCALL_ERROR;
+    };
+    match process.get_thread_by_resource_id_mut(thread_id) {
+        Some(thread) => match thread.get_handle() {
+            Ok(target) if target.boost() => 0,
+            _ => SYSCALL_BUSY, // Running, or otherwise not ready to take the quantum
+        },
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn write_console(buf: u64, len: u64) -> u64 {
+    // TODO: Validate against the process' page table once processes have their own
+    if !is_ram_range(buf, len) {
+        return SYSCALL_ERROR;
+    }
+    let console = UartHandler::new(uart0_base());
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+    console.write_all(bytes);
+    len
+}
+
+// None means the caller blocked
+fn transfer_result(result: Result<PipeTransfer, pipe::PipeError>) -> Option<u64> {
+    match result {
+        Ok(PipeTransfer::Done(count)) => Some(count as u64),
+        Ok(PipeTransfer::Blocked) => None,
+        Err(_) => Some(SYSCALL_ERROR),
+    }
+}
+
+fn write_pipe(pipe_id: ResourceId, buf: u64, len: u64, handle: &ThreadHandle) -> Option<u64> {
+    if !is_ram_range(buf, len) {
+        return Some(SYSCALL_ERROR);
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+    transfer_result(pipe::write(pipe_id, bytes, handle))
+}
+
+fn read_pipe(pipe_id: ResourceId, buf: u64, len: u64, handle: &ThreadHandle) -> Option<u64> {
+    if !is_ram_range(buf, len) {
+        return Some(SYSCALL_ERROR);
     }
+    let bytes = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len as usize) };
+    transfer_result(pipe::read(pipe_id, bytes, handle))
 }
 
 #[allow(unused)]
@@ -1,11 +1,19 @@
 use crate::{
+    consts::{DEFAULT_QUANTUM_MS, MAX_HARTS, RUNAWAY_PREEMPTIONS, STARVATION_THRESHOLD},
+    interrupt::IS_INTERRUPT_MASK,
     println,
-    resource::Resource,
+    resource::{Resource, ResourceId},
     sync::{Mutex, MutexGuardMut, MutexLockError},
     syscall::exit,
-    time::set_timecmp_delay_ms,
+    time::{get_time, rearm_timer_delay_ms, TimerWheelError, QUANTUM_TIMER},
+};
+use core::{
+    error::Error,
+    fmt::Display,
+    marker::PhantomData,
+    ptr::{self, addr_of},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering},
 };
-use core::{error::Error, fmt::Display, ptr::addr_of};
 
 use super::context::{activate_context, ActivationResult, RegisterContext};
 
@@ -14,16 +22,83 @@ pub enum ThreadState {
     Interrupted,
     Running,
     Ready,
+    Blocked,
     Zombie,
 }
 
+// What a blocked thread is waiting for. Whatever makes it happen wakes every thread waiting on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitReason {
+    PipeReadable(ResourceId),
+    PipeWritable(ResourceId),
+}
+
+// The ABI requires sp to be 16 byte aligned at every call
+const STACK_ALIGNMENT: u64 = 16;
+
+const NOT_WAITING: u64 = 0;
+
+impl WaitReason {
+    // Never NOT_WAITING, the kind goes above the packed id
+    fn encode(self) -> u64 {
+        match self {
+            WaitReason::PipeReadable(id) => 1 << 32 | id.pack() as u64,
+            WaitReason::PipeWritable(id) => 2 << 32 | id.pack() as u64,
+        }
+    }
+}
+
+// Where a thread picks back up after a trap
+#[derive(Clone, Copy, Debug)]
+pub enum ResumeMode {
+    Retry, // Re-run the trapping instruction, e.g. after an interrupt or a fixed up fault
+    Skip,  // Continue past the trapping instruction, e.g. after an ecall
+}
+
+// Time of the most recent activation on any hart, for the scheduler watchdog
+pub static LAST_ACTIVATION_TIME: AtomicU64 = AtomicU64::new(0);
+
+// The thread each hart is running, or handling a trap for. Set by activate and left in place when
+// the thread traps back, so trap handlers can find it, until the scheduler calls
+// clear_current_thread.
+static CURRENT_THREAD: [AtomicPtr<ThreadControlBlock>; MAX_HARTS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_HARTS];
+
+pub fn clear_current_thread(hart_id: u64) {
+    CURRENT_THREAD[hart_id as usize].store(ptr::null_mut(), Ordering::Release);
+}
+
+// Only meaningful inside a trap handler on hart_id, where the scheduler still holds the thread's
+// handle, so no other hart can touch it. The result aliases that handle (and the activation
+// result), so the caller must not use either of them while the reference is alive, and must not
+// keep it past the point where the handler returns.
+#[allow(dead_code)]
+pub unsafe fn current_thread<'a>(hart_id: u64) -> Option<&'a mut ThreadControlBlock> {
+    CURRENT_THREAD[hart_id as usize]
+        .load(Ordering::Acquire)
+        .as_mut()
+}
+
 pub struct ThreadControlBlock {
     registers: RegisterContext,
     pc: u64,
     state: ThreadState,
     id: u16,
+    owning_process_id: u16,
     priority: u16,
     need: u32,
+    fpu_dirty: bool,
+    cpu_ticks: u64,
+    quantum_ms: u64,
+    consecutive_preemptions: u32, // Since the thread last gave up a hart by itself
+    // Set without the handle, for threads that were busy when their process exited
+    kill_pending: AtomicBool,
+    // An encoded WaitReason, so wakers can tell what a busy thread waits on without its handle
+    waiting_on: AtomicU64,
+    // Like kill_pending, for wakeups that found the thread busy waiting on the same reason
+    wake_pending: AtomicBool,
+    // Bits other threads have set for this one to find, kept until it checks them
+    pending_notifications: AtomicU32,
     handle_lock: Mutex<()>,
 }
 
@@ -32,15 +107,47 @@ pub struct ThreadActivationResult<'a> {
     pub cause: u64,
 }
 
+impl ThreadActivationResult<'_> {
+    pub fn is_interrupt(&self) -> bool {
+        self.cause & IS_INTERRUPT_MASK > 0
+    }
+
+    // The cause without the interrupt bit
+    pub fn code(&self) -> u64 {
+        self.cause & !IS_INTERRUPT_MASK
+    }
+}
+
+impl Display for ThreadActivationResult<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Process {} thread {} trapped at {:#010x}: {} {}",
+            self.thread.owning_process_id,
+            self.thread.id,
+            self.thread.pc,
+            match self.is_interrupt() {
+                true => "interrupt",
+                false => "exception",
+            },
+            self.code()
+        )
+    }
+}
+
+// Only valid on the hart that claimed it. Both the guard and the raw pointer already make this
+// !Send and !Sync, the marker keeps it that way if either changes.
 pub struct ThreadHandle<'a> {
     _guard: MutexGuardMut<'a, ()>,
     thread: *mut ThreadControlBlock,
+    _not_send: PhantomData<*const ()>,
 }
 
 #[derive(Debug)]
 pub enum ThreadActivationError {
     FailedToClaim(ThreadHandleClaimError),
     ThreadNotReady(ThreadState),
+    FailedToArmQuantum(TimerWheelError),
 }
 
 #[derive(Debug)]
@@ -48,12 +155,35 @@ pub enum ThreadResolveInterruptError {
     ThreadNotInterrupted(ThreadState),
 }
 
+#[derive(Debug)]
+pub struct InvalidTransition {
+    pub from: ThreadState,
+    pub to: ThreadState,
+}
+
+impl ThreadState {
+    pub fn can_become(self, next: ThreadState) -> bool {
+        matches!(
+            (self, next),
+            (ThreadState::Ready, ThreadState::Running)
+                | (ThreadState::Ready, ThreadState::Zombie)
+                | (ThreadState::Running, ThreadState::Interrupted)
+                | (ThreadState::Interrupted, ThreadState::Ready)
+                | (ThreadState::Interrupted, ThreadState::Blocked)
+                | (ThreadState::Interrupted, ThreadState::Zombie)
+                | (ThreadState::Blocked, ThreadState::Ready)
+                | (ThreadState::Blocked, ThreadState::Zombie)
+        )
+    }
+}
+
 impl Display for ThreadState {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             ThreadState::Interrupted => write!(f, "Interrupted"),
             ThreadState::Running => write!(f, "Running"),
             ThreadState::Ready => write!(f, "Ready"),
+            ThreadState::Blocked => write!(f, "Blocked"),
             ThreadState::Zombie => write!(f, "Zombie"),
         }
     }
@@ -68,6 +198,9 @@ impl Display for ThreadActivationError {
                 "Thread state must be 'Ready', but the state is '{}'.",
                 state
             ),
+            Self::FailedToArmQuantum(err) => {
+                write!(f, "Failed to arm the scheduling quantum:\n{}", err)
+            }
         }
     }
 }
@@ -84,10 +217,33 @@ impl Display for ThreadResolveInterruptError {
     }
 }
 
+impl Display for InvalidTransition {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Thread cannot go from '{}' to '{}'.", self.from, self.to)
+    }
+}
+
+impl Error for InvalidTransition {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+
+    fn provide<'a>(&'a self, _request: &mut core::error::Request<'a>) {}
+}
+
 impl Error for ThreadActivationError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             Self::FailedToClaim(err) => Some(err),
+            Self::FailedToArmQuantum(err) => Some(err),
             _ => None,
         }
     }
@@ -103,6 +259,37 @@ impl Error for ThreadActivationError {
     fn provide<'a>(&'a self, _request: &mut core::error::Request<'a>) {}
 }
 
+#[derive(Debug)]
+pub enum ThreadCreationError {
+    InvalidStackBase(u64),
+}
+
+impl Display for ThreadCreationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ThreadCreationError::InvalidStackBase(stack_base) => {
+                write!(f, "Stack base {:#x} can't be used as a stack.", stack_base)
+            }
+        }
+    }
+}
+
+impl Error for ThreadCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+
+    fn provide<'a>(&'a self, _request: &mut core::error::Request<'a>) {}
+}
+
 #[derive(Debug)]
 pub enum ThreadHandleClaimError {
     HandleAlreadyClaimed(MutexLockError),
@@ -136,22 +323,43 @@ impl Error for ThreadHandleClaimError {
 
 pub struct CandidateThread<'a> {
     pub best: u32,
+    pub index: usize,
     pub handle: Option<ThreadHandle<'a>>,
 }
 
 impl<'a> CandidateThread<'a> {
-    pub fn new(best: u32, handle: Option<ThreadHandle<'a>>) -> CandidateThread<'a> {
+    pub fn new(best: u32, index: usize, handle: Option<ThreadHandle<'a>>) -> CandidateThread<'a> {
         CandidateThread {
             best: best,
+            index,
             handle: handle,
         }
     }
+
+    // Ties go to the thread closest after the last scheduled one, so threads with equal need take
+    // turns instead of favouring lower indices
+    pub fn is_beaten_by(
+        &self,
+        need: u32,
+        index: usize,
+        last_scheduled_index: usize,
+        slots: usize,
+    ) -> bool {
+        let distance = |index: usize| (index + slots - last_scheduled_index - 1) % slots;
+        match self.handle {
+            None => true,
+            Some(_) => {
+                need > self.best || (need == self.best && distance(index) < distance(self.index))
+            }
+        }
+    }
 }
 
 impl<'a> Default for CandidateThread<'a> {
     fn default() -> Self {
         Self {
             best: 0,
+            index: 0,
             handle: None,
         }
     }
@@ -161,21 +369,51 @@ impl<'a> ThreadControlBlock {
     pub fn new(
         code: extern "C" fn() -> u64,
         id: u16,
+        owning_process_id: u16,
         priority: u16,
         stack_base: u64,
-    ) -> ThreadControlBlock {
+    ) -> Result<ThreadControlBlock, ThreadCreationError> {
+        ThreadControlBlock::new_at(code as u64, id, owning_process_id, priority, stack_base)
+    }
+
+    // The stack grows down, so rounding down keeps it inside the memory it was given. Anything that
+    // rounds down to zero was never a real stack.
+    pub fn align_stack_base(stack_base: u64) -> Result<u64, ThreadCreationError> {
+        match stack_base & !(STACK_ALIGNMENT - 1) {
+            0 => Err(ThreadCreationError::InvalidStackBase(stack_base)),
+            aligned => Ok(aligned),
+        }
+    }
+
+    pub fn new_at(
+        entry: u64,
+        id: u16,
+        owning_process_id: u16,
+        priority: u16,
+        stack_base: u64,
+    ) -> Result<ThreadControlBlock, ThreadCreationError> {
+        let stack_base = ThreadControlBlock::align_stack_base(stack_base)?;
         let mut tcb = ThreadControlBlock {
             registers: RegisterContext::all_zero(),
-            pc: code as u64,
+            pc: entry,
             state: ThreadState::Ready,
             id: id,
+            owning_process_id,
             priority: priority,
             need: priority as u32,
+            fpu_dirty: false,
+            cpu_ticks: 0,
+            quantum_ms: DEFAULT_QUANTUM_MS,
+            consecutive_preemptions: 0,
+            kill_pending: AtomicBool::new(false),
+            waiting_on: AtomicU64::new(NOT_WAITING),
+            wake_pending: AtomicBool::new(false),
+            pending_notifications: AtomicU32::new(0),
             handle_lock: Mutex::new(()),
         };
         tcb.registers.sp = stack_base;
         tcb.registers.ra = exit as u64;
-        tcb
+        Ok(tcb)
     }
 
     pub fn get_handle(&mut self) -> Result<ThreadHandle<'_>, ThreadHandleClaimError> {
@@ -184,82 +422,249 @@ impl<'a> ThreadControlBlock {
             Ok(handle) => Ok(ThreadHandle {
                 _guard: handle,
                 thread: t,
+                _not_send: PhantomData,
             }),
             Err(mutex_err) => return Err(ThreadHandleClaimError::HandleAlreadyClaimed(mutex_err)),
         }
     }
 
+    // Every state change goes through here, so illegal ones are caught in one place
+    fn set_state(&mut self, next: ThreadState) -> Result<(), InvalidTransition> {
+        match self.state.can_become(next) {
+            true => {
+                self.state = next;
+                Ok(())
+            }
+            false => Err(InvalidTransition {
+                from: self.state,
+                to: next,
+            }),
+        }
+    }
+
     fn activate(&mut self, hart_id: u64) -> Result<ThreadActivationResult, ThreadActivationError> {
+        // Checked up front so the quantum is only armed for a thread that will actually run
+        if !self.state.can_become(ThreadState::Running) {
+            return Err(ThreadActivationError::ThreadNotReady(self.state));
+        }
+        if let Err(err) = rearm_timer_delay_ms(self.quantum_ms, QUANTUM_TIMER) {
+            return Err(ThreadActivationError::FailedToArmQuantum(err));
+        }
+        self.need = self.priority as u32;
+        if let Err(err) = self.set_state(ThreadState::Running) {
+            return Err(ThreadActivationError::ThreadNotReady(err.from));
+        }
+        CURRENT_THREAD[hart_id as usize].store(self, Ordering::Release);
+        let start = unsafe { get_time() };
+        LAST_ACTIVATION_TIME.fetch_max(start, Ordering::Relaxed);
+        unsafe {
+            let result: ActivationResult = activate_context(
+                self.pc,
+                addr_of!(self.registers) as u64,
+                hart_id,
+                self.fpu_dirty as u64,
+            );
+            self.cpu_ticks += get_time() - start;
+            self.pc = result.pc;
+            if let Err(err) = self.set_state(ThreadState::Interrupted) {
+                println!("{}", err);
+            }
+            Ok(ThreadActivationResult {
+                thread: self,
+                cause: result.cause,
+            })
+        }
+    }
+
+    fn consider(&mut self) -> Option<u32> {
+        if self.kill_if_pending() {
+            return None;
+        }
+        if let ThreadState::Blocked = self.state {
+            if self.wake_pending.swap(false, Ordering::AcqRel) {
+                self.unblock();
+            }
+        }
         match self.state {
             ThreadState::Ready => {
-                self.need = self.priority as u32;
-                self.state = ThreadState::Running;
-                unsafe {
-                    set_timecmp_delay_ms(1000);
-                    let result: ActivationResult =
-                        activate_context(self.pc, addr_of!(self.registers) as u64, hart_id);
-                    self.pc = result.pc;
-                    self.state = ThreadState::Interrupted;
-                    Ok(ThreadActivationResult {
-                        thread: self,
-                        cause: result.cause,
-                    })
+                self.need = self.need.saturating_add(self.priority as u32);
+                // Starved threads outrank everything else, regardless of priority
+                match self.need >= STARVATION_THRESHOLD {
+                    true => Some(u32::MAX),
+                    false => Some(self.need),
                 }
             }
-            _ => Err(ThreadActivationError::ThreadNotReady(self.state)),
+            _ => None,
         }
     }
 
-    fn consider(&mut self, best: u32) -> Option<u32> {
+    // Makes a ready thread outrank everything else next time it is considered
+    fn boost(&mut self) -> bool {
         match self.state {
             ThreadState::Ready => {
-                self.need += self.priority as u32;
-                if self.need > best {
-                    Some(self.need)
-                } else {
-                    None
-                }
+                self.need = u32::MAX;
+                true
             }
-            _ => None,
+            _ => false,
         }
     }
 
-    pub fn get_args(&self) -> [u64; 2] {
-        [self.registers.a0, self.registers.a1]
+    // The syscall code in a0, followed by up to seven arguments
+    pub fn get_args(&self) -> [u64; 8] {
+        core::array::from_fn(|n| self.registers.arg(n))
+    }
+
+    pub fn registers(&self) -> &RegisterContext {
+        &self.registers
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn owning_process_id(&self) -> u16 {
+        self.owning_process_id
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn cpu_ticks(&self) -> u64 {
+        self.cpu_ticks
     }
 
     fn set_return_val(&mut self, val: u64) {
-        self.registers.a0 = val;
+        self.registers.set_arg(0, val);
+    }
+
+    // A thread that keeps using up its whole quantum has its priority halved, so it stops crowding
+    // out threads that yield. Returns whether it was demoted.
+    fn note_preemption(&mut self) -> bool {
+        self.consecutive_preemptions += 1;
+        if self.consecutive_preemptions < RUNAWAY_PREEMPTIONS {
+            return false;
+        }
+        self.consecutive_preemptions = 0;
+        self.priority = (self.priority / 2).max(1);
+        println!(
+            "Thread {} of process {} was preempted {} times in a row, priority lowered to {}",
+            self.id, self.owning_process_id, RUNAWAY_PREEMPTIONS, self.priority
+        );
+        true
+    }
+
+    fn note_yield(&mut self) {
+        self.consecutive_preemptions = 0;
+    }
+
+    // Threads may only shorten their quantum, so they can't hog a hart. Returns the old quantum.
+    fn set_quantum_ms(&mut self, quantum_ms: u64) -> Option<u64> {
+        match quantum_ms {
+            1..=DEFAULT_QUANTUM_MS => Some(core::mem::replace(&mut self.quantum_ms, quantum_ms)),
+            _ => None,
+        }
+    }
+
+    // Threads start with the FPU off, so their first FPU instruction traps as an illegal
+    // instruction. Returns false if the FPU was already on, meaning the instruction really was
+    // illegal.
+    fn enable_fpu(&mut self) -> bool {
+        !core::mem::replace(&mut self.fpu_dirty, true)
     }
 
     pub fn get_need(&self) -> u32 {
         self.need
     }
 
+    pub fn request_kill(&self) {
+        self.kill_pending.store(true, Ordering::Release);
+    }
+
+    fn kill_if_pending(&mut self) -> bool {
+        let pending = self.kill_pending.load(Ordering::Acquire);
+        if pending {
+            self.kill();
+        }
+        pending
+    }
+
     fn kill(&mut self) {
         println!("Killing thread with id {}", self.id);
-        match self.state {
-            ThreadState::Running => panic!("Tried to kill running thread with id: {}", self.id),
-            _ => self.state = ThreadState::Zombie,
+        if let Err(err) = self.set_state(ThreadState::Zombie) {
+            println!("Failed to kill thread with id {}: {}", self.id, err);
         }
     }
 
-    fn resolve_interrupt(&mut self, synchronous: bool) -> Result<(), ThreadResolveInterruptError> {
-        match self.state {
-            ThreadState::Interrupted => {
-                self.state = ThreadState::Ready;
-                if synchronous {
-                    self.pc += 4;
+    // The trapping instruction is retried on wake, so a blocking syscall just checks again
+    fn block(&mut self, reason: WaitReason) -> Result<(), InvalidTransition> {
+        self.set_state(ThreadState::Blocked)?;
+        self.waiting_on.store(reason.encode(), Ordering::Release);
+        self.consecutive_preemptions = 0;
+        Ok(())
+    }
+
+    fn wake(&mut self, reason: WaitReason) -> bool {
+        match self.is_waiting_on(reason) {
+            true => self.unblock(),
+            false => false,
+        }
+    }
+
+    // Spurious wakeups are fine, since the thread re-checks whatever it was waiting for
+    fn unblock(&mut self) -> bool {
+        match self.set_state(ThreadState::Ready) {
+            Ok(()) => {
+                self.waiting_on.store(NOT_WAITING, Ordering::Release);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_waiting_on(&self, reason: WaitReason) -> bool {
+        self.waiting_on.load(Ordering::Acquire) == reason.encode()
+    }
+
+    pub fn request_wake(&self) {
+        self.wake_pending.store(true, Ordering::Release);
+    }
+
+    // Needs no handle, so the target can be running or blocked anywhere
+    pub fn notify(&self, notifications: u32) {
+        self.pending_notifications
+            .fetch_or(notifications, Ordering::Release);
+    }
+
+    pub fn take_notifications(&self) -> u32 {
+        self.pending_notifications.swap(0, Ordering::Acquire)
+    }
+
+    fn resolve_interrupt(&mut self, mode: ResumeMode) -> Result<(), ThreadResolveInterruptError> {
+        match self.set_state(ThreadState::Ready) {
+            Ok(()) => {
+                if let ResumeMode::Skip = mode {
+                    self.pc += instruction_length(self.pc);
                 }
                 Ok(())
             }
-            _ => Err(ThreadResolveInterruptError::ThreadNotInterrupted(
-                self.state,
-            )),
+            Err(err) => Err(ThreadResolveInterruptError::ThreadNotInterrupted(err.from)),
         }
     }
 }
 
+// Compressed instructions (like c.ebreak) are 2 bytes, everything else we trap on is 4
+fn instruction_length(pc: u64) -> u64 {
+    match unsafe { (pc as *const u16).read_volatile() } & 0b11 {
+        0b11 => 4,
+        _ => 2,
+    }
+}
+
 impl<'a> ThreadHandle<'a> {
     pub fn activate(&self, hart_id: u64) -> Result<ThreadActivationResult, ThreadActivationError> {
         unsafe {
@@ -268,10 +673,17 @@ impl<'a> ThreadHandle<'a> {
         }
     }
 
-    pub fn consider(&self, best: u32) -> Option<u32> {
+    pub fn consider(&self) -> Option<u32> {
+        unsafe {
+            assert!((*self.thread).handle_lock.is_held());
+            (*self.thread).consider()
+        }
+    }
+
+    pub fn boost(&self) -> bool {
         unsafe {
             assert!((*self.thread).handle_lock.is_held());
-            (*self.thread).consider(best)
+            (*self.thread).boost()
         }
     }
 
@@ -282,6 +694,27 @@ impl<'a> ThreadHandle<'a> {
         }
     }
 
+    pub fn note_preemption(&self) -> bool {
+        unsafe {
+            assert!((*self.thread).handle_lock.is_held());
+            (*self.thread).note_preemption()
+        }
+    }
+
+    pub fn note_yield(&self) {
+        unsafe {
+            assert!((*self.thread).handle_lock.is_held());
+            (*self.thread).note_yield()
+        }
+    }
+
+    pub fn set_quantum_ms(&self, quantum_ms: u64) -> Option<u64> {
+        unsafe {
+            assert!((*self.thread).handle_lock.is_held());
+            (*self.thread).set_quantum_ms(quantum_ms)
+        }
+    }
+
     pub fn kill(&self) {
         unsafe {
             assert!((*self.thread).handle_lock.is_held());
@@ -289,15 +722,47 @@ impl<'a> ThreadHandle<'a> {
         }
     }
 
-    pub fn resolve_interrupt(&self, synchronous: bool) -> Result<(), ThreadResolveInterruptError> {
+    pub fn kill_if_pending(&self) -> bool {
+        unsafe {
+            assert!((*self.thread).handle_lock.is_held());
+            (*self.thread).kill_if_pending()
+        }
+    }
+
+    pub fn block(&self, reason: WaitReason) -> Result<(), InvalidTransition> {
+        unsafe {
+            assert!((*self.thread).handle_lock.is_held());
+            (*self.thread).block(reason)
+        }
+    }
+
+    pub fn is_handle_of(&self, thread: &ThreadControlBlock) -> bool {
+        ptr::eq(self.thread, thread)
+    }
+
+    pub fn wake(&self, reason: WaitReason) -> bool {
+        unsafe {
+            assert!((*self.thread).handle_lock.is_held());
+            (*self.thread).wake(reason)
+        }
+    }
+
+    pub fn enable_fpu(&self) -> bool {
+        unsafe {
+            assert!((*self.thread).handle_lock.is_held());
+            (*self.thread).enable_fpu()
+        }
+    }
+
+    pub fn resolve_interrupt(&self, mode: ResumeMode) -> Result<(), ThreadResolveInterruptError> {
         unsafe {
             assert!((*self.thread).handle_lock.is_held());
-            (*self.thread).resolve_interrupt(synchronous)
+            (*self.thread).resolve_interrupt(mode)
         }
     }
 
-    pub fn resolve_interrupt_or_kill(&self, synchronous: bool) {
-        match self.resolve_interrupt(synchronous) {
+    pub fn resolve_interrupt_or_kill(&self, mode: ResumeMode) {
+        match self.resolve_interrupt(mode) {
             Ok(_) => {}
             Err(_) => {
                 self.kill();
@@ -311,8 +776,10 @@ impl Resource for Option<ThreadControlBlock> {
     fn exhausted(&self) -> bool {
         match self {
             None => true,
+            // A hart that just killed the thread still holds its handle until it's done with it.
+            // Handles are only claimed under SCHEDULER_LOCK, which reclaiming slots also holds.
             Some(thread) => match thread.state {
-                ThreadState::Zombie => true,
+                ThreadState::Zombie => !thread.handle_lock.is_held(),
                 _ => false,
             },
         }
@@ -1,18 +1,214 @@
-use core::arch::global_asm;
+use core::{
+    arch::global_asm,
+    error::Error,
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-pub const TIMER_FREQ: u64 = 400_0000; // ticks / second
-const US_TO_TICKS: u64 = TIMER_FREQ / 100_0000; // ticks / microsecond
+use crate::{
+    consts::{LOCK_RANK_TIMER_WHEEL, MAX_HARTS},
+    smp::current_hart_id,
+    sync::Mutex,
+};
 
+const DEFAULT_TIMER_FREQ: u64 = 400_0000; // ticks / second
+
+// Replaced by the device tree's timebase-frequency, if it has one
+static TIMER_FREQ: AtomicU64 = AtomicU64::new(DEFAULT_TIMER_FREQ);
+
+pub const MAX_PENDING_TIMERS: usize = 16;
+
+pub type TimerCallbackId = usize;
+
+pub const QUANTUM_TIMER: TimerCallbackId = 0;
+pub const WATCHDOG_TIMER: TimerCallbackId = 1;
+
+#[derive(Clone, Copy)]
+struct TimerEntry {
+    deadline: u64,
+    callback_id: TimerCallbackId,
+}
+
+// Entries are kept sorted by deadline, so the earliest is always at index 0
+pub struct TimerWheel {
+    entries: [TimerEntry; MAX_PENDING_TIMERS],
+    len: usize,
+}
+
+#[derive(Debug)]
+pub enum TimerWheelError {
+    Full,
+}
+
+impl Display for TimerWheelError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Full => write!(f, "Timer wheel has no space for another timer."),
+        }
+    }
+}
+
+impl Error for TimerWheelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+
+    fn provide<'a>(&'a self, _request: &mut core::error::Request<'a>) {}
+}
+
+impl TimerWheel {
+    pub const fn new() -> TimerWheel {
+        TimerWheel {
+            entries: [TimerEntry {
+                deadline: 0,
+                callback_id: 0,
+            }; MAX_PENDING_TIMERS],
+            len: 0,
+        }
+    }
+
+    pub fn arm(
+        &mut self,
+        deadline: u64,
+        callback_id: TimerCallbackId,
+    ) -> Result<(), TimerWheelError> {
+        if self.len == MAX_PENDING_TIMERS {
+            return Err(TimerWheelError::Full);
+        }
+        let position = self.entries[..self.len]
+            .iter()
+            .position(|entry| entry.deadline > deadline)
+            .unwrap_or(self.len);
+        self.entries.copy_within(position..self.len, position + 1);
+        self.entries[position] = TimerEntry {
+            deadline,
+            callback_id,
+        };
+        self.len += 1;
+        self.program();
+        Ok(())
+    }
+
+    pub fn cancel(&mut self, callback_id: TimerCallbackId) -> bool {
+        let before = self.len;
+        let mut kept = 0;
+        for index in 0..self.len {
+            if self.entries[index].callback_id != callback_id {
+                self.entries[kept] = self.entries[index];
+                kept += 1;
+            }
+        }
+        self.len = kept;
+        if kept != before {
+            self.program();
+        }
+        kept != before
+    }
+
+    pub fn rearm(
+        &mut self,
+        deadline: u64,
+        callback_id: TimerCallbackId,
+    ) -> Result<(), TimerWheelError> {
+        self.cancel(callback_id);
+        self.arm(deadline, callback_id)
+    }
+
+    pub fn expire(&mut self, now: u64, mut on_expire: impl FnMut(TimerCallbackId)) {
+        let expired = self.entries[..self.len]
+            .iter()
+            .take_while(|entry| entry.deadline <= now)
+            .count();
+        self.entries[..expired]
+            .iter()
+            .for_each(|entry| on_expire(entry.callback_id));
+        self.entries.copy_within(expired..self.len, 0);
+        self.len -= expired;
+        self.program();
+    }
+
+    fn program(&self) {
+        // Programming the comparator to the maximum value disarms it
+        let next = match self.len {
+            0 => u64::MAX,
+            _ => self.entries[0].deadline,
+        };
+        unsafe { set_timecmp(next) }
+    }
+}
+
+// Each hart has its own comparator, so each gets its own wheel
+pub static TIMER_WHEELS: [Mutex<TimerWheel>; MAX_HARTS] =
+    [const { Mutex::new_ranked(TimerWheel::new(), LOCK_RANK_TIMER_WHEEL) }; MAX_HARTS];
+
+pub fn local_timer_wheel() -> &'static Mutex<TimerWheel> {
+    &TIMER_WHEELS[current_hart_id()]
+}
+
+pub fn set_timer_freq(freq: u64) {
+    if freq > 0 {
+        TIMER_FREQ.store(freq, Ordering::Relaxed);
+    }
+}
+
+// Conversions saturate rather than wrap, since a wrapped deadline lands in the past and fires
+// immediately. A deadline of u64::MAX never fires.
+pub fn us_to_ticks(us: u64) -> u64 {
+    let ticks = us as u128 * TIMER_FREQ.load(Ordering::Relaxed) as u128 / 100_0000;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+pub fn delay_to_deadline(delay_us: u64) -> u64 {
+    unsafe { get_time().saturating_add(us_to_ticks(delay_us)) }
+}
+
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    us_to_ticks(ms.saturating_mul(1000))
+}
+
+pub fn rearm_timer_deadline(
+    deadline: u64,
+    callback_id: TimerCallbackId,
+) -> Result<(), TimerWheelError> {
+    local_timer_wheel().with_mut(|wheel| wheel.rearm(deadline, callback_id))
+}
+
+pub fn rearm_timer_delay_ms(
+    delay_ms: u64,
+    callback_id: TimerCallbackId,
+) -> Result<(), TimerWheelError> {
+    rearm_timer_deadline(
+        delay_to_deadline(delay_ms.saturating_mul(1000)),
+        callback_id,
+    )
+}
+
+pub fn handle_timer_interrupt(on_expire: impl FnMut(TimerCallbackId)) {
+    local_timer_wheel().with_mut(|wheel| wheel.expire(unsafe { get_time() }, on_expire))
+}
+
+#[allow(dead_code)]
 pub fn set_timecmp_delay(delay_us: u64) {
-    unsafe { set_timecmp(get_time() + delay_us * US_TO_TICKS) }
+    unsafe { set_timecmp(delay_to_deadline(delay_us)) }
 }
 
+#[allow(dead_code)]
 pub fn set_timecmp_delay_ms(delay_ms: u64) {
-    set_timecmp_delay(delay_ms * 1000);
+    set_timecmp_delay(delay_ms.saturating_mul(1000));
 }
 
 extern "C" {
     pub fn get_time() -> u64;
+    #[cfg(feature = "boot_selftest")]
+    pub fn get_cycles() -> u64;
     pub fn set_timecmp(time: u64);
 }
 
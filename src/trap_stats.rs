@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{exception::exception_name, interrupt::interrupt_name, println};
+
+// Standard causes are all below this. Anything else is counted in one extra slot at the end.
+const COUNTED_CAUSES: usize = 16;
+
+static EXCEPTION_COUNTS: [AtomicU64; COUNTED_CAUSES + 1] =
+    [const { AtomicU64::new(0) }; COUNTED_CAUSES + 1];
+static INTERRUPT_COUNTS: [AtomicU64; COUNTED_CAUSES + 1] =
+    [const { AtomicU64::new(0) }; COUNTED_CAUSES + 1];
+
+fn record(counts: &[AtomicU64; COUNTED_CAUSES + 1], code: u64) {
+    counts[(code as usize).min(COUNTED_CAUSES)].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_exception(code: u64) {
+    record(&EXCEPTION_COUNTS, code)
+}
+
+pub fn record_interrupt(code: u64) {
+    record(&INTERRUPT_COUNTS, code)
+}
+
+fn print_counts(counts: &[AtomicU64; COUNTED_CAUSES + 1], name: fn(u64) -> &'static str) {
+    for (code, count) in counts.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        if count == 0 {
+            continue;
+        }
+        match code {
+            COUNTED_CAUSES => println!("  {:>10}  Other", count),
+            _ => println!("  {:>10}  {}", count, name(code as u64)),
+        }
+    }
+}
+
+// Only causes that have fired at least once are listed
+pub fn print_trap_stats() {
+    println!("Interrupts:");
+    print_counts(&INTERRUPT_COUNTS, interrupt_name);
+    println!("Exceptions:");
+    print_counts(&EXCEPTION_COUNTS, exception_name);
+}
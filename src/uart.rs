@@ -1,8 +1,13 @@
 use crate::io::{Readable, Writable};
-use core::fmt::Write;
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-// TODO: Don't hard code this
-pub const UART0_BASE: u64 = 0x1000_0000;
+const DEFAULT_UART0_BASE: u64 = 0x1000_0000;
+
+// Replaced by the device tree's stdout-path, if it has one
+static UART0_BASE: AtomicU64 = AtomicU64::new(DEFAULT_UART0_BASE);
 
 const RBR_OFFSET: isize = 0x00;
 const THR_OFFSET: isize = 0x00;
@@ -49,16 +54,19 @@ impl Writable<u8> for UartHandler {
 
 impl Write for UartHandler {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for c in s.bytes() {
-            let mut write_successful = self.write(c);
-            while write_successful.is_err() {
-                write_successful = self.write(c);
-            }
-        }
+        self.write_all(s.as_bytes());
         Ok(())
     }
 }
 
+pub fn uart0_base() -> u64 {
+    UART0_BASE.load(Ordering::Relaxed)
+}
+
+pub fn set_uart0_base(base: u64) {
+    UART0_BASE.store(base, Ordering::Relaxed);
+}
+
 impl UartHandler {
     pub fn new(base: u64) -> UartHandler {
         let base_ptr = base as *const u8;
@@ -74,14 +82,44 @@ impl UartHandler {
             handler
         }
     }
+
+    // Writes until the transmitter is full, returning how many bytes made it out
+    pub fn write_slice(&self, bytes: &[u8]) -> usize {
+        bytes
+            .iter()
+            .take_while(|&&byte| self.write(byte).is_ok())
+            .count()
+    }
+
+    // Reads whatever has already arrived, returning how many bytes were read
+    pub fn read_into(&self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buf.len() {
+            match self.read() {
+                Some(byte) => buf[count] = byte,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+
+    pub fn write_all(&self, bytes: &[u8]) {
+        let mut remaining = bytes;
+        while let Some((&first, rest)) = remaining.split_first() {
+            // Wait for space for one byte, then push out as much as the transmitter takes
+            self.write_blocking(first);
+            remaining = &rest[self.write_slice(rest)..];
+        }
+    }
 }
 
 #[macro_export]
 macro_rules! print {
     ($($args:tt)+) => ({
         use core::fmt::Write;
-        use crate::uart::{UART0_BASE, UartHandler};
-        let mut uart_out = UartHandler::new(UART0_BASE);
+        use crate::uart::{uart0_base, UartHandler};
+        let mut uart_out = UartHandler::new(uart0_base());
         let _ = write!(&mut uart_out, $($args)+);
     });
 }